@@ -1,75 +1,299 @@
+use std::collections::{HashMap, VecDeque};
 use std::error::Error as StdError;
 use std::fs::File;
 use std::hash::{DefaultHasher, Hash, Hasher};
-use std::io::{BufReader, Read, Take};
+use std::io;
+use std::io::{BufReader, Cursor, ErrorKind, Read, Seek, SeekFrom, Take};
 use std::str::FromStr;
-use chrono::DateTime;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+use chrono::{DateTime, Utc};
 use headers_accept::Accept;
+use itertools::Itertools;
 use varnish::vcl::backend::{Serve, Transfer};
-use varnish::vcl::ctx::Ctx;
+use log::Level;
+use serde::Serialize;
+use varnish::vcl::ctx::{Ctx, LogTag};
 use varnish::vcl::http::HTTP;
 use crate::cache::{Cache, FetchResult};
-use crate::config::Config;
+use crate::conditionals;
+use crate::conditionals::{Conditionals, Precondition};
+use crate::config::{Config, DuplicateSlashes, Size};
 use crate::error::Error;
+use crate::images;
+use crate::limiter::{FileLimiter, FilePermit};
+use crate::meta::Meta;
+use crate::query::QueryParams;
+use crate::range::Range;
+use crate::srcset::Srcset;
+use crate::utils;
 
 pub struct FileBackend {
     config: Config,
     cache: Cache,
+    limiter: FileLimiter,
+    content_etags: RwLock<HashMap<String, ContentEtag>>,
+}
+
+//content etag of the version of a file identified by its inode, size and modification date
+struct ContentEtag {
+    inode: u64,
+    size: u64,
+    last_modified: DateTime<Utc>,
+    etag: String,
 }
 
 impl FileBackend {
     pub fn new(config: Config, cache: Cache) -> Self {
+        let limiter = FileLimiter::new(config.max_open_files);
+
         FileBackend {
             config,
             cache,
+            limiter,
+            content_etags: RwLock::new(HashMap::new()),
         }
     }
+
+    pub fn cache(&self) -> &Cache {
+        &self.cache
+    }
 }
 
 impl FileBackend {
-    fn get_data(&self, ctx: &mut Ctx) -> Result<Option<FileTransfer>, Error> {
+    //`resolved` describes the file chosen to answer the request for the logs
+    fn get_data(&self, ctx: &mut Ctx, resolved: &mut Option<String>) -> Result<Option<BodyTransfer>, Error> {
         let bereq = ctx.http_bereq.as_ref().unwrap();
         let bereq_method = bereq.method().unwrap_or("");
-        let bereq_url = urlencoding::decode(bereq.url().unwrap())?;
+        let bereq_url = bereq.url().unwrap();
+
+        if bereq_url.len() > self.config.max_url_length.unwrap_or(2048) {
+            respond!(ctx, 414);
+        }
+
+        //100-continue is the only expectation defined, the others can not be met
+        if bereq.header("expect").is_some_and(|expect| !expect.trim().eq_ignore_ascii_case("100-continue")) {
+            respond!(ctx, 417);
+        }
+
+        //server wide options are answered without looking for an image
+        if bereq_url == "*" && bereq_method == "OPTIONS" {
+            let beresp = ctx.http_beresp.as_mut().unwrap();
+            beresp.set_header("Allow", "GET, HEAD")?;
+            respond!(ctx, 204);
+        }
+
+        //absolute and authority form targets or other `*` requests are only ever meant for proxies
+        if !bereq_url.starts_with('/') {
+            return Err(Error::BadRequest(format!("Invalid request target {}", bereq_url)));
+        }
+
+        let (bereq_url, bereq_query) = utils::split_query(bereq_url);
+        //equivalent urls only differing by their slashes can share a single cache entry
+        let collapsed = utils::collapse_slashes(bereq_url);
+        let bereq_url = match self.config.duplicate_slashes.unwrap_or(DuplicateSlashes::Keep) {
+            DuplicateSlashes::Redirect if collapsed != bereq_url => {
+                let location = match bereq_query {
+                    Some(query) => format!("{}?{}", collapsed, query),
+                    None => collapsed.into_owned(),
+                };
+
+                let beresp = ctx.http_beresp.as_mut().unwrap();
+                beresp.set_header("Location", &location)?;
+                beresp.set_header("Content-Length", "0")?;
+                respond!(ctx, 301);
+            }
+            DuplicateSlashes::Collapse => collapsed.as_ref(),
+            _ => bereq_url,
+        };
+        let bereq_url = utils::decode_path(bereq_url)?;
+
+        let params = QueryParams::parse(bereq_query)?;
         let beresp = ctx.http_beresp.as_mut().unwrap();
         let mut transfer = None;
 
         let pattern = self.config.url_regex.as_ref().expect("Badly initialized config");
 
-        if let Some(captures) = pattern.captures(bereq_url.as_ref()) {
+        let (bereq_url, forced_extension) = self.config.strip_format_suffix(&bereq_url);
+
+        if let Some(captures) = pattern.captures(bereq_url) {
+            //images taken down for legal reasons are blocked in every size and format
+            if self.config.blocked_paths_regex.as_ref().is_some_and(|blocked| blocked.is_match(&captures["path"])) {
+                if let Some(authority) = &self.config.blocking_authority {
+                    beresp.set_header("Link", &format!("<{}>; rel=\"blocked-by\"", authority))?;
+                }
+
+                respond!(ctx, 451);
+            }
+
             if !self.config.sizes.get(&captures["size"]).map_or(false, |p| p.matches(&captures["path"])) {
                 respond!(ctx, 404);
             }
 
-            let accept = self.parse_accept_header(bereq);
-            let Some(result) = self.cache.get(&captures["path"], &captures["size"], accept)? else {
+            if let Some(filter) = &params.srcset {
+                if !self.cache.contains(&captures["path"])? {
+                    respond!(ctx, 404);
+                }
+
+                let ext = captures.name("ext").map(|ext| ext.as_str());
+                let srcset = Srcset::new(&self.config, &captures["path"], ext, filter);
+                let data = MemoryTransfer::new(serde_json::to_vec(&srcset)?);
+
+                return self.respond_json(beresp, bereq_method, data);
+            }
+
+            //unknown images get the same 404 whatever the method and the number of open files
+            if !self.cache.contains(&captures["path"])? {
                 respond!(ctx, 404);
+            }
+
+            //only get requests open the file, the other requests are answered
+            //from its metadata so they are never turned away
+            let with_body = bereq_method == "GET";
+            let permit = if with_body {
+                let Some(permit) = self.limiter.acquire() else {
+                    beresp.set_header("Retry-After", "1")?;
+                    respond!(ctx, 503);
+                };
+
+                Some(permit)
+            } else {
+                None
             };
 
-            let (is_304, etag) = process_cache_headers(&bereq, &result);
-            if is_304 {
-                beresp.set_status(304);
+            let result = if params.original {
+                self.cache.get_original(&captures["path"], with_body)?
+            } else {
+                let accept = match forced_extension {
+                    Some(extension) => Accept::from_str(&extension.to_media_type().to_string()).ok(),
+                    None => self.parse_accept_header(bereq),
+                };
+                let size = self.select_size(bereq, &captures["path"], &captures["size"]);
+                let size = if self.config.save_data_qualities.is_some() && wants_to_save_data(bereq) {
+                    Config::save_data_size_name(size)
+                } else {
+                    size.to_owned()
+                };
+
+                self.cache.get(&captures["path"], &size, accept, with_body)?
+            };
+
+            let Some(mut result) = result else {
+                respond!(ctx, 404);
+            };
+
+            *resolved = Some(format!("{} as {}", result.path, result.mime));
+
+            if let (Some(permit), Some(data)) = (permit, &mut result.data) {
+                data.hold(permit);
             }
 
-            beresp.set_proto("HTTP/1.1")?;
-            beresp.set_header("ETag", &etag)?;
-            beresp.set_header("Last-Modified", &result.last_modified.format("%a, %d %b %Y %H:%M:%S GMT").to_string())?;
-            beresp.set_header("Content-Length", &result.data.size().to_string())?;
-            beresp.set_header("Content-Type", result.mime)?;
-            beresp.set_header("Vary", "Accept")?;
-            beresp.set_header("Cache-Control", if result.is_optimized {
-                "public, max-age=31536000, immutable"
-            } else {
-                "no-cache"
-            })?;
+            let etag = self.etag(&result)?;
+            let weak_etags = self.config.weak_etags.unwrap_or(false);
+            let conditionals = Conditionals::from_headers(bereq);
+            let precondition = conditionals::evaluate_preconditions(&conditionals, &etag, weak_etags, &result.last_modified);
+            let size = result.size;
+
+            if params.meta {
+                let meta = Meta::new(&result, &etag)?;
+                let data = MemoryTransfer::new(serde_json::to_vec(&meta)?);
+
+                return self.respond_json(beresp, bereq_method, data);
+            }
+
+            beresp.set_proto(self.config.proto.as_deref().unwrap_or("HTTP/1.1"))?;
 
+            //neither 405 nor 412 responses describe the image, they do not get its headers
             if bereq_method != "HEAD" && bereq_method != "GET" {
-                beresp.set_status(405);
+                beresp.set_header("Allow", "GET, HEAD")?;
+                beresp.set_header("Content-Length", "0")?;
+                respond!(ctx, 405);
+            } else if precondition == Precondition::Failed {
+                beresp.set_header("Content-Length", "0")?;
+                respond!(ctx, 412);
+            }
+
+            self.set_representation_headers(beresp, &captures["path"], &etag, &result)?;
+
+            if let Some(base_url) = &self.config.canonical_base_url {
+                //equivalent urls get the same canonical url regardless of
+                //their query string except for the parameters changing the image
+                let ext = captures.name("ext").map(|ext| ext.as_str());
+                let mut canonical = format!("{}{}", base_url.trim_end_matches('/'), self.config.build_url(&captures["size"], &captures["path"], ext));
+                if params.original {
+                    canonical.push_str("?original=1");
+                }
+
+                beresp.set_header("Link", &format!("<{}>; rel=\"canonical\"", canonical))?;
+            }
+
+            for (_, urls) in self.config.preloads_regex.iter().filter(|(pattern, _)| pattern.is_match(&captures["path"])) {
+                for url in urls {
+                    beresp.set_header("Link", &format!("<{}>; rel=preload; as=image", url))?;
+                }
+            }
+
+            //304 responses do not carry a body
+            if precondition == Precondition::NotModified {
+                beresp.set_status(304);
             } else {
-                beresp.set_status(200);
+                //the range only applies to the representation identified by the etag, a client
+                //resuming the download of the original image after it got optimized gets it whole.
+                //head requests get the same headers without the body
+                let range = if conditionals::matches_if_range(&conditionals, &etag, weak_etags, &result.last_modified) {
+                    Range::parse(bereq.header("range"), size)
+                } else {
+                    Range::Full
+                };
+
+                match range {
+                    Range::Full => {
+                        beresp.set_header("Content-Length", &size.to_string())?;
+                        beresp.set_status(200);
+                    }
+                    Range::Partial { start, end } => {
+                        if let Some(data) = &mut result.data {
+                            data.seek_range(start, end)?;
+                        }
 
-                if bereq_method == "GET" {
-                    transfer = Some(result.data);
+                        beresp.set_header("Content-Length", &(end - start + 1).to_string())?;
+                        beresp.set_header("Content-Range", &format!("bytes {}-{}/{}", start, end, size))?;
+                        beresp.set_status(206);
+                    }
+                    Range::Multiple(ranges) => {
+                        //the boundary only depends on the image and the ranges so
+                        //that head and get requests announce the same length
+                        let boundary = format!("{:016x}", utils::stable_hash(format!("{}{:?}", etag, ranges)));
+                        let length = match &mut result.data {
+                            Some(data) => {
+                                data.seek_ranges(&ranges, &boundary, result.mime);
+                                data.size() as u64
+                            }
+                            None => multipart_parts(&ranges, &boundary, result.mime, size).iter().map(Part::size).sum(),
+                        };
+
+                        beresp.unset_header("Content-Type");
+                        beresp.set_header("Content-Type", &format!("multipart/byteranges; boundary={}", boundary))?;
+                        beresp.set_header("Content-Length", &length.to_string())?;
+                        beresp.set_status(206);
+                    }
+                    Range::Unsatisfiable => {
+                        beresp.set_header("Content-Length", "0")?;
+                        beresp.set_header("Content-Range", &format!("bytes */{}", size))?;
+                        respond!(ctx, 416);
+                    }
+                }
+
+                if let Some(mut data) = result.data {
+                    if let Some(seconds) = self.config.transfer_timeout {
+                        data.set_deadline(Instant::now() + Duration::from_secs(seconds));
+                    }
+
+                    if self.config.verify_transfer_size.unwrap_or(false) {
+                        data.verify_size();
+                    }
+
+                    transfer = Some(BodyTransfer::File(data));
                 }
             }
         } else {
@@ -79,7 +303,223 @@ impl FileBackend {
         Ok(transfer)
     }
 
+    //json documents describe images, they are never cached as the
+    //image they describe can get optimized at any moment
+    fn respond_json(&self, beresp: &mut HTTP, method: &str, mut data: MemoryTransfer) -> Result<Option<BodyTransfer>, Error> {
+        beresp.set_proto(self.config.proto.as_deref().unwrap_or("HTTP/1.1"))?;
+        beresp.set_header("Content-Type", "application/json")?;
+        beresp.set_header("Cache-Control", "no-cache")?;
+
+        if method != "HEAD" && method != "GET" {
+            beresp.set_header("Allow", "GET, HEAD")?;
+            beresp.set_header("Content-Length", "0")?;
+            beresp.set_status(405);
+
+            return Ok(None);
+        }
+
+        beresp.set_header("Content-Length", &data.size().to_string())?;
+        beresp.set_status(200);
+
+        if method == "GET" {
+            if let Some(chunk_size) = self.config.memory_chunk_size {
+                data.set_chunk_size(chunk_size);
+            }
+
+            Ok(Some(BodyTransfer::Memory(data)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    //headers describing the image being served, they are set before any method or status
+    //specific branching so that HEAD and GET requests always get the same validators
+    fn set_representation_headers(&self, beresp: &mut HTTP, path: &str, etag: &str, result: &FetchResult) -> Result<(), Error> {
+        //some cdns mangle the etags of the images they transform, leading to revalidation
+        //loops, these images are only revalidated with their modification date
+        let etagless = self.config.etagless_paths_regex.as_ref().is_some_and(|etagless| etagless.is_match(path));
+        if !etagless && self.config.weak_etags.unwrap_or(false) {
+            beresp.set_header("ETag", &format!("W/\"{}\"", etag))?;
+        } else if !etagless {
+            beresp.set_header("ETag", &format!("\"{}\"", etag))?;
+        }
+        beresp.set_header("Last-Modified", &utils::http_date(&result.last_modified))?;
+        beresp.set_header("Content-Type", result.mime)?;
+        beresp.set_header("Accept-Ranges", "bytes")?;
+        let vary = self.vary();
+        if !vary.is_empty() {
+            beresp.set_header("Vary", &vary)?;
+        }
+        if self.config.client_hints.unwrap_or(false) {
+            beresp.set_header("Accept-CH", "Sec-CH-Width, Sec-CH-DPR")?;
+
+            //browsers retry the request with the hints when they did not send them
+            if self.config.critical_client_hints.unwrap_or(false) {
+                beresp.set_header("Critical-CH", "Sec-CH-Width, Sec-CH-DPR")?;
+            }
+        }
+
+        beresp.set_header("Cache-Control", &self.config.cache_control(path, result.is_optimized))?;
+
+        if let Some(origin) = &self.config.timing_allow_origin {
+            beresp.set_header("Timing-Allow-Origin", origin)?;
+        }
+
+        Ok(())
+    }
+
+    fn log(&self, ctx: &mut Ctx, level: Level, message: &str) {
+        if self.config.logs_to_varnish(level) {
+            ctx.log(if level == Level::Error { LogTag::Error } else { LogTag::Debug }, message);
+        }
+    }
+
+    //picks the smallest size at least as wide as the width the client hinted at,
+    //the dpr alone scales the width of the requested size
+    fn select_size<'a>(&'a self, bereq: &HTTP, path: &str, requested: &'a str) -> &'a str {
+        if !self.config.client_hints.unwrap_or(false) {
+            return requested;
+        }
+
+        //only the hints listed in the vary header are read, the legacy `width` and `dpr`
+        //headers would otherwise put a variant in the cache for every other client
+        let hint = |name: &str| bereq.header(name)
+            .and_then(|value| value.trim().parse::<f32>().ok())
+            .filter(|value| *value > 0.0);
+
+        let width = match (hint("sec-ch-width"), hint("sec-ch-dpr")) {
+            (Some(width), _) => width,
+            (None, Some(dpr)) => self.config.sizes[requested].width as f32 * dpr,
+            (None, None) => return requested,
+        };
+
+        let candidates = self.config.sizes.iter()
+            .filter(|(_, size)| size.matches(path))
+            .sorted_by_key(|(name, size)| (size.width, size.height, name.to_string()))
+            .collect::<Vec<(&String, &Size)>>();
+
+        candidates.iter()
+            .find(|(_, size)| size.width as f32 >= width)
+            .or(candidates.last())
+            .map_or(requested, |(name, _)| name.as_str())
+    }
+
+    //changing the cache generation changes the etags of optimized images,
+    //clients revalidating them get the images produced by the new encoders
+    fn etag(&self, result: &FetchResult) -> Result<String, Error> {
+        let etag = self.file_etag(result)?;
+
+        match &self.config.cache_generation {
+            Some(generation) if result.is_optimized => Ok(format!("{:016x}", utils::stable_hash(format!("{}-{}", etag, generation)))),
+            _ => Ok(etag),
+        }
+    }
+
+    //etags based on the content of the files are the same on every server
+    //serving the same images, the default ones are cheaper to compute
+    fn file_etag(&self, result: &FetchResult) -> Result<String, Error> {
+        if !self.config.content_etags.unwrap_or(false) {
+            return Ok(generate_etag(result));
+        }
+
+        //files are only hashed once per version
+        let is_same_version = |cached: &ContentEtag| cached.inode == result.inode
+            && cached.size == result.size
+            && cached.last_modified == result.last_modified;
+
+        if let Some(cached) = self.content_etags.read()?.get(&result.path).filter(|cached| is_same_version(cached)) {
+            return Ok(cached.etag.clone());
+        }
+
+        let etag = format!("{:016x}", utils::stable_hash_file(&result.path)?);
+        self.content_etags.write()?.insert(result.path.clone(), ContentEtag {
+            inode: result.inode,
+            size: result.size,
+            last_modified: result.last_modified,
+            etag: etag.clone(),
+        });
+
+        Ok(etag)
+    }
+
+    fn wants_json_errors(&self, ctx: &Ctx) -> bool {
+        let bereq = ctx.http_bereq.as_ref().unwrap();
+        self.config.json_errors.unwrap_or(false) || bereq.header("accept").is_some_and(|accept| accept.contains("application/json"))
+    }
+
+    fn json_error(&self, ctx: &mut Ctx) -> Result<Option<BodyTransfer>, Box<dyn StdError>> {
+        let bereq = ctx.http_bereq.as_ref().unwrap();
+        let is_head = bereq.method() == Some("HEAD");
+        let path = utils::split_query(bereq.url().unwrap_or("")).0.to_owned();
+        let beresp = ctx.http_beresp.as_mut().unwrap();
+        let status = beresp.status().and_then(|status| status.parse::<u16>().ok()).unwrap_or(0);
+
+        let error = match status {
+            400 => "bad_request",
+            404 => "not_found",
+            405 => "method_not_allowed",
+            412 => "precondition_failed",
+            414 => "uri_too_long",
+            415 => "unsupported_media_type",
+            416 => "range_not_satisfiable",
+            417 => "expectation_failed",
+            451 => "unavailable_for_legal_reasons",
+            500 => "internal_error",
+            503 => "service_unavailable",
+            _ => return Ok(None),
+        };
+
+        let data = MemoryTransfer::new(serde_json::to_vec(&ErrorBody { error, path: &path })?);
+        beresp.unset_header("Content-Type");
+        beresp.unset_header("Content-Length");
+        beresp.set_header("Content-Type", "application/json")?;
+        beresp.set_header("Content-Length", &data.size().to_string())?;
+        if !self.config.json_errors.unwrap_or(false) {
+            let vary = vary_with(beresp.header("Vary"), "Accept");
+            beresp.unset_header("Vary");
+            beresp.set_header("Vary", &vary)?;
+        }
+
+        //head requests get the same headers without the body
+        if is_head {
+            Ok(None)
+        } else {
+            Ok(Some(BodyTransfer::Memory(data)))
+        }
+    }
+
+    fn vary(&self) -> String {
+        let mut vary = Vec::new();
+
+        //the format is only negotiated when it is not kept
+        if !self.config.keep_format.unwrap_or(false) {
+            vary.push("Accept");
+        }
+
+        if self.config.user_agent_denylist_regex.is_some() {
+            vary.push("User-Agent");
+        }
+
+        if self.config.save_data_qualities.is_some() {
+            vary.push("Save-Data");
+        }
+
+        if self.config.client_hints.unwrap_or(false) {
+            vary.push("Sec-CH-Width, Sec-CH-DPR");
+        }
+
+        vary.join(", ")
+    }
+
     fn parse_accept_header(&self, bereq: &HTTP) -> Option<Accept> {
+        //some clients advertise formats they do not render properly,
+        //they get the default format as if they did not send any accept header
+        if let (Some(denylist), Some(user_agent)) = (&self.config.user_agent_denylist_regex, bereq.header("user-agent")) {
+            if denylist.is_match(user_agent) {
+                return None;
+            }
+        }
+
         match bereq.header("accept") {
             Some(accept) if accept.trim() != "*/*" => Accept::from_str(accept).ok(),
             _ => None
@@ -87,40 +527,266 @@ impl FileBackend {
     }
 }
 
-impl Serve<FileTransfer> for FileBackend {
+impl Serve<BodyTransfer> for FileBackend {
     fn get_type(&self) -> &str {
         "impress"
     }
 
-    fn get_headers(&self, ctx: &mut Ctx) -> Result<Option<FileTransfer>, Box<dyn StdError>> {
-        match self.get_data(ctx) {
-            Ok(transfer) => Ok(transfer),
+    fn get_headers(&self, ctx: &mut Ctx) -> Result<Option<BodyTransfer>, Box<dyn StdError>> {
+        if self.config.server_header.unwrap_or(false) {
+            let beresp = ctx.http_beresp.as_mut().unwrap();
+            beresp.set_header("Server", concat!("impress/", env!("CARGO_PKG_VERSION")))?;
+        }
+
+        if self.config.formats_header.unwrap_or(false) {
+            let beresp = ctx.http_beresp.as_mut().unwrap();
+            beresp.set_header("X-Press-Formats", &images::supported_outputs().join(", "))?;
+        }
+
+        let mut resolved = None;
+        let transfer = match self.get_data(ctx, &mut resolved) {
+            Ok(transfer) => {
+                let bereq = ctx.http_bereq.as_ref().unwrap();
+                let beresp = ctx.http_beresp.as_ref().unwrap();
+                let mut message = format!("{} {} -> {}", bereq.method().unwrap_or(""), bereq.url().unwrap_or(""), beresp.status().unwrap_or(""));
+                if let Some(resolved) = &resolved {
+                    message.push_str(&format!(" ({})", resolved));
+                }
+
+                self.log(ctx, Level::Debug, &message);
+
+                transfer
+            }
+            Err(Error::BadRequest(message)) => {
+                let url = ctx.http_bereq.as_ref().unwrap().url().unwrap_or("").to_owned();
+                self.log(ctx, Level::Debug, &format!("Bad request {}: {}", url, message));
+
+                let beresp = ctx.http_beresp.as_mut().unwrap();
+                beresp.set_status(400);
+                debug_header!(self.config, beresp, "error", message);
+
+                None
+            }
+            Err(Error::UnsupportedMediaType) => {
+                let beresp = ctx.http_beresp.as_mut().unwrap();
+                beresp.set_status(415);
+
+                None
+            }
             Err(e) => {
+                let url = ctx.http_bereq.as_ref().unwrap().url().unwrap_or("").to_owned();
+                self.log(ctx, Level::Error, &format!("Failed to serve {}: {}", url, e));
+
                 let beresp = ctx.http_beresp.as_mut().unwrap();
                 beresp.set_status(500);
-                beresp.set_header("error", &e.to_string())?;
+                debug_header!(self.config, beresp, "error", e.to_string());
 
-                Ok(None)
+                None
             }
+        };
+
+        //unknown images are looked up in memory, the ttl only spares the round trips to the backend
+        if let Some(seconds) = self.config.not_found_ttl {
+            let beresp = ctx.http_beresp.as_mut().unwrap();
+            if beresp.status() == Some("404") {
+                beresp.set_header("Cache-Control", &format!("public, max-age={}", seconds))?;
+            }
+        }
+
+        //error responses without a body of their own get a json description of the error
+        let transfer = if transfer.is_none() && self.wants_json_errors(ctx) {
+            self.json_error(ctx)?
+        } else {
+            transfer
+        };
+
+        //headers configured for the final status of the response
+        let beresp = ctx.http_beresp.as_mut().unwrap();
+        let status = beresp.status().and_then(|status| status.parse::<u16>().ok());
+        for (name, value) in status.and_then(|status| self.config.status_headers.as_ref()?.get(&status)).into_iter().flatten() {
+            beresp.set_header(name, value)?;
         }
+
+        Ok(transfer)
     }
 }
 
-pub struct FileTransfer(Take<BufReader<File>>);
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: &'a str,
+    path: &'a str,
+}
+
+//adds a header to a vary header value unless it is already listed
+fn vary_with(vary: Option<&str>, header: &str) -> String {
+    match vary {
+        Some(vary) if vary.split(',').any(|listed| listed.trim().eq_ignore_ascii_case(header)) => vary.to_owned(),
+        Some(vary) if !vary.trim().is_empty() => format!("{}, {}", vary, header),
+        _ => header.to_owned(),
+    }
+}
+
+struct Part {
+    prefix: Vec<u8>,
+    range: Option<(u64, u64)>,
+}
+
+impl Part {
+    fn size(&self) -> u64 {
+        self.prefix.len() as u64 + self.range.map_or(0, |(start, end)| end - start + 1)
+    }
+}
+
+//every range of a multipart/byteranges body is preceded by its own headers
+fn multipart_parts(ranges: &[(u64, u64)], boundary: &str, mime: &str, file_size: u64) -> VecDeque<Part> {
+    let mut parts = VecDeque::new();
+
+    for (i, (start, end)) in ranges.iter().enumerate() {
+        let separator = if i == 0 { "" } else { "\r\n" };
+        let prefix = format!("{}--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n", separator, boundary, mime, start, end, file_size);
+
+        parts.push_back(Part { prefix: prefix.into_bytes(), range: Some((*start, *end)) });
+    }
+
+    parts.push_back(Part { prefix: format!("\r\n--{}--\r\n", boundary).into_bytes(), range: None });
+
+    parts
+}
+
+pub struct FileTransfer {
+    reader: Take<BufReader<File>>,
+    prefix: Cursor<Vec<u8>>,
+    parts: VecDeque<Part>,
+    file_size: u64,
+    verify_size: bool,
+    deadline: Option<Instant>,
+    _permit: Option<FilePermit>,
+}
 
 impl FileTransfer {
     pub fn new(file: File, size: u64) -> FileTransfer {
-        FileTransfer(BufReader::new(file).take(size))
+        FileTransfer {
+            reader: BufReader::new(file).take(size),
+            prefix: Cursor::new(Vec::new()),
+            parts: VecDeque::new(),
+            file_size: size,
+            verify_size: false,
+            deadline: None,
+            _permit: None,
+        }
     }
 
     pub fn size(&self) -> usize {
-        self.0.limit() as usize
+        let prefix = self.prefix.get_ref().len() as u64 - self.prefix.position();
+        let parts = self.parts.iter().map(Part::size).sum::<u64>();
+
+        (prefix + self.reader.limit() + parts) as usize
+    }
+
+    pub fn seek_range(&mut self, start: u64, end: u64) -> Result<(), Error> {
+        self.reader.get_mut().seek(SeekFrom::Start(start))?;
+        self.reader.set_limit(end - start + 1);
+
+        Ok(())
+    }
+
+    //every range is streamed from the file as a multipart/byteranges body part
+    pub fn seek_ranges(&mut self, ranges: &[(u64, u64)], boundary: &str, mime: &str) {
+        self.reader.set_limit(0);
+        self.parts = multipart_parts(ranges, boundary, mime, self.file_size);
+    }
+
+    pub fn hold(&mut self, permit: FilePermit) {
+        self._permit = Some(permit);
+    }
+
+    //clients still reading the body after the deadline get their
+    //transfer aborted so they do not keep the file and permit forever
+    pub fn set_deadline(&mut self, deadline: Instant) {
+        self.deadline = Some(deadline);
+    }
+
+    //files modified after being opened would have their body truncated or padded
+    //to the announced content length, they get aborted instead once their end is read
+    pub fn verify_size(&mut self) {
+        self.verify_size = true;
     }
 }
 
 impl Transfer for FileTransfer {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, Box<dyn StdError>> {
-        self.0.read(buf).map_err(|e| e.into())
+        if self.deadline.is_some_and(|deadline| Instant::now() > deadline) {
+            return Err(io::Error::new(ErrorKind::TimedOut, "Transfer deadline exceeded").into());
+        }
+
+        loop {
+            if self.prefix.position() < self.prefix.get_ref().len() as u64 {
+                return Ok(self.prefix.read(buf)?);
+            }
+
+            if self.reader.limit() > 0 {
+                break;
+            }
+
+            let Some(part) = self.parts.pop_front() else {
+                break;
+            };
+
+            if let Some((start, end)) = part.range {
+                self.reader.get_mut().seek(SeekFrom::Start(start))?;
+                self.reader.set_limit(end - start + 1);
+            }
+
+            self.prefix = Cursor::new(part.prefix);
+        }
+
+        let read = self.reader.read(buf)?;
+
+        if self.verify_size {
+            if read == 0 && self.reader.limit() > 0 {
+                return Err(io::Error::new(ErrorKind::UnexpectedEof, "File shrank during transfer").into());
+            }
+
+            if read > 0 && self.reader.limit() == 0 && self.reader.get_ref().get_ref().metadata()?.len() != self.file_size {
+                return Err(io::Error::other("File size changed during transfer").into());
+            }
+        }
+
+        Ok(read)
+    }
+
+    fn len(&self) -> Option<usize> {
+        Some(self.size())
+    }
+}
+
+pub struct MemoryTransfer {
+    cursor: Cursor<Vec<u8>>,
+    chunk_size: Option<usize>,
+}
+
+impl MemoryTransfer {
+    pub fn new(data: Vec<u8>) -> MemoryTransfer {
+        MemoryTransfer {
+            cursor: Cursor::new(data),
+            chunk_size: None,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.cursor.get_ref().len()
+    }
+
+    //maximum number of bytes copied by each read, the whole buffer given by varnish is filled otherwise
+    pub fn set_chunk_size(&mut self, chunk_size: usize) {
+        self.chunk_size = Some(chunk_size);
+    }
+}
+
+impl Transfer for MemoryTransfer {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Box<dyn StdError>> {
+        let length = self.chunk_size.map_or(buf.len(), |chunk_size| chunk_size.min(buf.len()));
+        self.cursor.read(&mut buf[..length]).map_err(|e| e.into())
     }
 
     fn len(&self) -> Option<usize> {
@@ -128,26 +794,163 @@ impl Transfer for FileTransfer {
     }
 }
 
-fn process_cache_headers(bereq: &HTTP, result: &FetchResult) -> (bool, String) {
-    let etag = generate_etag(result);
+pub enum BodyTransfer {
+    File(FileTransfer),
+    Memory(MemoryTransfer),
+}
 
-    if let Some(inm) = bereq.header("if-none-match") {
-        if inm == etag || (inm.starts_with("W/") && inm[2..] == etag) {
-            return (true, etag);
+impl Transfer for BodyTransfer {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Box<dyn StdError>> {
+        match self {
+            BodyTransfer::File(transfer) => transfer.read(buf),
+            BodyTransfer::Memory(transfer) => transfer.read(buf),
         }
-    } else if let Some(ims) = bereq.header("if-modified-since") {
-        if let Ok(t) = DateTime::parse_from_rfc2822(ims) {
-            if t > result.last_modified {
-                return (true, etag);
-            }
+    }
+
+    fn len(&self) -> Option<usize> {
+        match self {
+            BodyTransfer::File(transfer) => transfer.len(),
+            BodyTransfer::Memory(transfer) => transfer.len(),
         }
     }
+}
 
-    return (false, etag);
+fn wants_to_save_data(bereq: &HTTP) -> bool {
+    bereq.header("save-data").is_some_and(|save_data| save_data.trim().eq_ignore_ascii_case("on"))
 }
 
 fn generate_etag(result: &FetchResult) -> String {
     let mut h = DefaultHasher::new();
-    (result.inode, result.data.size(), result.last_modified.timestamp(), result.is_optimized).hash(&mut h);
+    (result.inode, result.size as usize, result.last_modified.timestamp(), result.is_optimized).hash(&mut h);
     h.finish().to_string()
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Write;
+    use super::*;
+
+    //every read of the transfer until it is done
+    fn reads(transfer: &mut impl Transfer, buffer_size: usize) -> Result<Vec<Vec<u8>>, Box<dyn StdError>> {
+        let mut reads = Vec::new();
+        let mut buf = vec![0; buffer_size];
+
+        loop {
+            let read = transfer.read(&mut buf)?;
+            if read == 0 {
+                return Ok(reads);
+            }
+
+            reads.push(buf[..read].to_vec());
+        }
+    }
+
+    #[test]
+    fn multiple_ranges_are_streamed_as_multipart() {
+        let path = std::env::temp_dir().join("impress_multipart_ranges.txt");
+        fs::write(&path, "0123456789abcdefghij").unwrap();
+
+        let mut transfer = FileTransfer::new(File::open(&path).unwrap(), 20);
+        transfer.seek_ranges(&[(0, 3), (10, 12)], "boundary", "image/jpeg");
+        let announced = transfer.size();
+        let body = reads(&mut transfer, 7).unwrap().concat();
+
+        fs::remove_file(&path).unwrap();
+
+        let expected = "--boundary\r\nContent-Type: image/jpeg\r\nContent-Range: bytes 0-3/20\r\n\r\n0123\
+            \r\n--boundary\r\nContent-Type: image/jpeg\r\nContent-Range: bytes 10-12/20\r\n\r\nabc\
+            \r\n--boundary--\r\n";
+
+        assert_eq!(String::from_utf8(body).unwrap(), expected);
+        assert_eq!(announced, expected.len());
+    }
+
+    #[test]
+    fn files_are_fully_transferred_whatever_the_buffer_size() {
+        let path = std::env::temp_dir().join("impress_buffer_sizes.txt");
+        let content = (0..10_000).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        fs::write(&path, &content).unwrap();
+
+        for buffer_size in [1, 7, 4096, 65536] {
+            let mut transfer = FileTransfer::new(File::open(&path).unwrap(), content.len() as u64);
+            let body = reads(&mut transfer, buffer_size).unwrap().concat();
+
+            assert_eq!(body, content, "buffer of {} bytes", buffer_size);
+        }
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn memory_transfers_are_read_in_chunks() {
+        let mut transfer = MemoryTransfer::new(b"0123456789".to_vec());
+        transfer.set_chunk_size(4);
+
+        let reads = reads(&mut transfer, 16).unwrap();
+        assert_eq!(reads, [b"0123".to_vec(), b"4567".to_vec(), b"89".to_vec()]);
+    }
+
+    #[test]
+    fn memory_transfers_are_read_byte_by_byte() {
+        let mut transfer = MemoryTransfer::new(b"0123".to_vec());
+        transfer.set_chunk_size(1);
+
+        let reads = reads(&mut transfer, 16).unwrap();
+        assert_eq!(reads.len(), 4);
+        assert_eq!(reads.concat(), b"0123");
+    }
+
+    #[test]
+    fn memory_chunks_are_limited_by_the_buffer() {
+        let mut transfer = MemoryTransfer::new(b"0123456789".to_vec());
+        transfer.set_chunk_size(8);
+
+        let reads = reads(&mut transfer, 3).unwrap();
+        assert!(reads.iter().all(|read| read.len() <= 3));
+        assert_eq!(reads.concat(), b"0123456789");
+    }
+
+    //transfer of a file whose size changes once it is opened
+    fn resized_transfer(name: &str, verify_size: bool, resize: impl FnOnce(&File)) -> Result<Vec<Vec<u8>>, Box<dyn StdError>> {
+        let path = std::env::temp_dir().join(format!("impress_{}.txt", name));
+        fs::write(&path, "0123456789abcdefghij").unwrap();
+
+        let mut transfer = FileTransfer::new(File::open(&path).unwrap(), 20);
+        if verify_size {
+            transfer.verify_size();
+        }
+
+        resize(&fs::OpenOptions::new().append(true).open(&path).unwrap());
+        let reads = reads(&mut transfer, 4);
+        fs::remove_file(&path).unwrap();
+
+        reads
+    }
+
+    #[test]
+    fn transfers_of_files_that_shrank_are_aborted() {
+        assert!(resized_transfer("shrank", true, |file| file.set_len(10).unwrap()).is_err());
+        assert_eq!(resized_transfer("shrank_unverified", false, |file| file.set_len(10).unwrap()).unwrap().concat(), b"0123456789");
+    }
+
+    #[test]
+    fn transfers_of_files_that_grew_are_aborted() {
+        assert!(resized_transfer("grew", true, |mut file| file.write_all(b"klmno").unwrap()).is_err());
+        assert_eq!(resized_transfer("grew_unverified", false, |mut file| file.write_all(b"klmno").unwrap()).unwrap().concat(), b"0123456789abcdefghij");
+    }
+
+    #[test]
+    fn transfers_of_unchanged_files_are_verified() {
+        assert_eq!(resized_transfer("unchanged", true, |_| ()).unwrap().concat(), b"0123456789abcdefghij");
+    }
+
+    #[test]
+    fn vary_headers_are_not_listed_twice() {
+        assert_eq!(vary_with(None, "Accept"), "Accept");
+        assert_eq!(vary_with(Some(""), "Accept"), "Accept");
+        assert_eq!(vary_with(Some("Accept"), "Accept"), "Accept");
+        assert_eq!(vary_with(Some("User-Agent, accept"), "Accept"), "User-Agent, accept");
+        assert_eq!(vary_with(Some("User-Agent"), "Accept"), "User-Agent, Accept");
+    }
+}
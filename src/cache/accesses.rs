@@ -0,0 +1,56 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use chrono::{DateTime, Utc};
+use crate::error::Error;
+
+//accesses of each optimized image since varnish started, by path. Images served
+//again only take the read lock, the write lock is taken for their first access
+#[derive(Clone, Default)]
+pub struct Accesses {
+    entries: Arc<RwLock<HashMap<String, Access>>>,
+}
+
+#[derive(Default)]
+struct Access {
+    last_access: AtomicI64,
+    hits: AtomicU64,
+}
+
+impl Access {
+    fn touch(&self) {
+        self.last_access.store(Utc::now().timestamp(), Ordering::Relaxed);
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Accesses {
+    pub fn record(&self, path: &str) -> Result<(), Error> {
+        if let Some(access) = self.entries.read()?.get(path) {
+            access.touch();
+            return Ok(());
+        }
+
+        self.entries.write()?.entry(path.to_owned()).or_default().touch();
+
+        Ok(())
+    }
+
+    //last time the image got served and the number of times it did
+    pub fn get(&self, path: &str) -> Result<Option<(DateTime<Utc>, u64)>, Error> {
+        let lock = self.entries.read()?;
+        let Some(access) = lock.get(path) else {
+            return Ok(None);
+        };
+
+        let last_access = DateTime::from_timestamp(access.last_access.load(Ordering::Relaxed), 0).unwrap_or_default();
+
+        Ok(Some((last_access, access.hits.load(Ordering::Relaxed))))
+    }
+
+    pub fn remove(&self, path: &str) -> Result<(), Error> {
+        self.entries.write()?.remove(path);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,202 @@
+use std::fs;
+use std::thread;
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use itertools::Itertools;
+use crate::cache::CacheData;
+use crate::cache::accesses::Accesses;
+use crate::config::{Config, Extension};
+
+const EVICTION_INTERVAL: Duration = Duration::from_secs(60);
+
+struct Entry {
+    image_id: String,
+    key: (String, Extension),
+    path: String,
+    bytes: u64,
+    last_access: DateTime<Utc>,
+}
+
+pub fn spawn(config: Config, data: CacheData, accesses: Accesses) {
+    if config.cache_max_size.is_none() && config.cache_ttl.is_none() {
+        return;
+    }
+
+    thread::spawn(move || {
+        loop {
+            thread::sleep(EVICTION_INTERVAL);
+            evict(&config, &data, &accesses);
+        }
+    });
+}
+
+//removes the optimized images that were not served for longer than the ttl, then
+//the least recently served ones until the cache fits in its maximum size
+fn evict(config: &Config, data: &CacheData, accesses: &Accesses) {
+    let entries = collect_entries(data, accesses);
+    let mut total_size = entries.iter().map(|entry| entry.bytes).sum::<u64>();
+    let expiration = config.cache_ttl.map(|ttl| Utc::now() - chrono::Duration::seconds(ttl as i64));
+
+    let mut to_evict = Vec::new();
+    for entry in entries.into_iter().sorted_by_key(|entry| entry.last_access) {
+        let is_expired = expiration.is_some_and(|expiration| entry.last_access < expiration);
+        let is_too_large = config.cache_max_size.is_some_and(|max_size| total_size > max_size);
+
+        if !is_expired && !is_too_large {
+            break;
+        }
+
+        total_size -= entry.bytes;
+        to_evict.push(entry);
+    }
+
+    if to_evict.is_empty() {
+        return;
+    }
+
+    //images optimized again since the entries were collected are kept
+    {
+        let mut lock = data.write().unwrap();
+        to_evict.retain(|entry| {
+            let Some(cache) = lock.get_mut(&entry.image_id) else {
+                return false;
+            };
+
+            if cache.optimized.get(&entry.key) != Some(&entry.path) {
+                return false;
+            }
+
+            cache.optimized.remove(&entry.key);
+            true
+        });
+    }
+
+    for entry in &to_evict {
+        let _ = accesses.remove(&entry.path);
+
+        //transfers still reading the file can finish as it is only unlinked
+        if let Err(error) = fs::remove_file(&entry.path) {
+            warn!("Failed to evict optimized image {}: {}", entry.path, error);
+        }
+    }
+
+    info!("Evicted {} optimized images from the cache", to_evict.len());
+}
+
+//images never served since varnish started were last accessed when they were optimized
+fn collect_entries(data: &CacheData, accesses: &Accesses) -> Vec<Entry> {
+    let lock = data.read().unwrap();
+
+    lock.iter()
+        .flat_map(|(image_id, cache)| cache.optimized.iter().map(move |(key, path)| (image_id, key, path)))
+        .filter_map(|(image_id, key, path)| {
+            let metadata = fs::metadata(path).ok()?;
+            let last_access = match accesses.get(path).ok()? {
+                Some((last_access, _)) => last_access,
+                None => DateTime::from(metadata.modified().ok()?),
+            };
+
+            Some(Entry {
+                image_id: image_id.to_owned(),
+                key: key.clone(),
+                path: path.to_owned(),
+                bytes: metadata.len(),
+                last_access,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::path::{Path, PathBuf};
+    use std::time::SystemTime;
+    use crate::cache::CacheImage;
+    use super::*;
+
+    //optimized images of 100 bytes last modified the given number of seconds ago
+    fn cache(name: &str, ages: &[u64]) -> (PathBuf, CacheData, Vec<String>) {
+        let directory = std::env::temp_dir().join(format!("impress_{}", name));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+
+        let data = CacheData::default();
+        let mut paths = Vec::new();
+
+        for (i, age) in ages.iter().enumerate() {
+            let path = directory.join(format!("{}.webp", i)).to_string_lossy().to_string();
+            fs::write(&path, [0; 100]).unwrap();
+            File::options().write(true).open(&path).unwrap()
+                .set_modified(SystemTime::now() - Duration::from_secs(*age))
+                .unwrap();
+
+            let mut image = CacheImage::new(directory.join(format!("{}.jpg", i)).to_string_lossy().to_string());
+            image.add(String::from("default"), Extension::WEBP, &path);
+            data.write().unwrap().insert(i.to_string(), image);
+            paths.push(path);
+        }
+
+        (directory, data, paths)
+    }
+
+    fn kept(data: &CacheData) -> Vec<String> {
+        data.read().unwrap().iter()
+            .filter(|(_, image)| !image.optimized.is_empty())
+            .map(|(image_id, _)| image_id.clone())
+            .sorted()
+            .collect()
+    }
+
+    #[test]
+    fn exceeding_the_maximum_size_evicts_the_oldest_images() {
+        let (directory, data, paths) = cache("evict_oldest", &[300, 200, 100]);
+        let config = Config { cache_max_size: Some(250), ..Config::default() };
+
+        evict(&config, &data, &Accesses::default());
+
+        assert_eq!(kept(&data), ["1", "2"]);
+        assert!(!Path::new(&paths[0]).exists());
+        assert!(Path::new(&paths[1]).exists());
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn recently_served_images_are_kept() {
+        let (directory, data, paths) = cache("evict_served", &[300, 200, 100]);
+        let config = Config { cache_max_size: Some(250), ..Config::default() };
+        let accesses = Accesses::default();
+        accesses.record(&paths[0]).unwrap();
+
+        evict(&config, &data, &accesses);
+
+        assert_eq!(kept(&data), ["0", "2"]);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn images_not_served_within_the_ttl_are_evicted() {
+        let (directory, data, _) = cache("evict_expired", &[300, 200, 100]);
+        let config = Config { cache_ttl: Some(150), ..Config::default() };
+
+        evict(&config, &data, &Accesses::default());
+
+        assert_eq!(kept(&data), ["2"]);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn images_fitting_in_the_cache_are_kept() {
+        let (directory, data, _) = cache("evict_nothing", &[300, 200, 100]);
+        let config = Config { cache_max_size: Some(300), cache_ttl: Some(400), ..Config::default() };
+
+        evict(&config, &data, &Accesses::default());
+
+        assert_eq!(kept(&data), ["0", "1", "2"]);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -1,13 +1,16 @@
-use std::path::PathBuf;
+use std::fs;
+use std::fs::File;
 use std::sync::mpsc::Receiver;
 use std::thread;
 use std::time::Duration;
+use image::ImageFormat;
 use rusty_pool::ThreadPool;
 use crate::cache::CacheData;
-use crate::config::{Config, Extension};
+use crate::config::{Config, Extension, Fit, FormatMismatch};
 use crate::error::Error;
 use crate::images;
 use crate::images::OptimizationConfig;
+use crate::pyramid;
 
 pub struct OptimizeImage {
     pub image_id: String,
@@ -35,28 +38,130 @@ pub fn spawn(config: Config, data: CacheData, rx: Receiver<OptimizeImage>) {
 }
 
 fn save_image(config: Config, cache: CacheData, image: OptimizeImage) -> Result<(), Error> {
-    let mut path = PathBuf::from(&config.cache_directory);
-    path.push(&image.size);
-    path.push(&image.image_id);
-    path.set_extension(image.extension.extensions().first().unwrap());
+    let path = config.cache_path(&image.size, &image.image_id, image.extension);
 
-    let Some(size) = config.sizes.get(&image.size) else {
+    let Some(size) = config.size(&image.size) else {
         return Error::err(format!("Unknown image size {}", image.size))
     };
 
-    let base_image_path = {
+    let (base_image_path, unsupported, has_sidecar) = {
         let lock = cache.read()?;
         let data = lock.get(&image.image_id).ok_or(Error::new("Image not found"))?;
 
-        data.base_image_path.clone()
+        (data.base_image_path.clone(), data.unsupported, data.sidecar(size, image.extension).is_some())
     };
 
-    let optimization_config = OptimizationConfig::new(size, image.extension, false);
-    let optimized = images::read(&base_image_path)?;
-    let optimized = images::resize(&optimized, size.width, size.height);
-    let optimized = images::optimize(&optimized, optimization_config)?;
+    if unsupported {
+        debug!("Image {} can not be decoded, it will not be optimized", image.image_id);
+        return Ok(());
+    }
 
-    images::write(&path, &optimized.data(), None)?;
+    if has_sidecar && !config.keep_format.unwrap_or(false) {
+        debug!("Image {} has a sidecar in size {}, it will not be optimized", image.image_id, image.size);
+
+        let mut lock = cache.write()?;
+        if let Some(cache) = lock.get_mut(&image.image_id) {
+            cache.skipped.insert((image.size, image.extension));
+        }
+
+        return Ok(());
+    }
+
+    //images still being uploaded are optimized on a later request once they are old enough
+    if let Some(seconds) = config.min_source_age {
+        let age = fs::metadata(&base_image_path)?.modified()?.elapsed().unwrap_or_default();
+        if age < Duration::from_secs(seconds) {
+            debug!("Image {} was modified too recently, it will not be optimized yet", image.image_id);
+            return Ok(());
+        }
+    }
+
+    //images that would be rejected must not get an optimized image served without any check
+    let policy = config.format_mismatch.unwrap_or(FormatMismatch::TrustContent);
+    let sniffed = images::sniff_format(&mut File::open(&base_image_path)?)?;
+    if let Err(Error::UnsupportedMediaType) = images::resolve_format(ImageFormat::from_path(&base_image_path)?, sniffed, policy) {
+        info!("Image {} does not match its extension, it will not be optimized", image.image_id);
+        set_passthrough(&cache, &image.image_id);
+
+        return Ok(());
+    }
+
+    let is_animated = match images::is_animated(&base_image_path) {
+        Ok(is_animated) => is_animated,
+        Err(Error::Corrupt(error)) => return set_corrupt(&cache, &image.image_id, &error),
+        Err(error) => return Err(error),
+    };
+
+    if is_animated {
+        info!("Image {} is animated, it will be served as is", image.image_id);
+        set_passthrough(&cache, &image.image_id);
+
+        return Ok(());
+    }
+
+    //resizing from the closest precomputed resolution is cheaper than from the original
+    let levels = pyramid::levels(&config, &base_image_path);
+    let source = match pyramid::closest(&levels, size) {
+        Some(level) if !size.upscale.unwrap_or(false) => &level.path,
+        _ => &base_image_path,
+    };
+
+    let trust_content = policy == FormatMismatch::TrustContent;
+    let optimized = match images::read(source, trust_content) {
+        Ok(optimized) => optimized,
+        Err(Error::Corrupt(error)) => return set_corrupt(&cache, &image.image_id, &error),
+        Err(error) => return Err(error),
+    };
+    let optimized = images::resize(&optimized, size.width, size.height, size.upscale.unwrap_or(false), size.fit.unwrap_or(Fit::Inside), config.background_rgb);
+    let optimized = if image.extension == Extension::JPEG {
+        images::flatten(optimized, config.background_rgb)
+    } else {
+        optimized
+    };
+
+    let pixels = optimized.width() as u64 * optimized.height() as u64;
+    let quality = size.quality_for(image.extension, pixels);
+    let mut optimization_config = OptimizationConfig::with_quality(image.extension, quality, false);
+    optimization_config.validate()?;
+
+    //webp sources keep their quality along with their format
+    let keep_format = config.keep_format.unwrap_or(false);
+    if let OptimizationConfig::Webp { lossless, .. } = &mut optimization_config {
+        *lossless = keep_format;
+    }
+
+    let (width, height) = (optimized.width(), optimized.height());
+    let timeout = config.encode_timeout.map(Duration::from_secs);
+    let optimized = match images::optimize_with_timeout(optimized, optimization_config, timeout) {
+        Ok(optimized) => optimized,
+        Err(Error::Timeout) => {
+            //the encoder can not be interrupted, trying again would end up
+            //with more threads stuck on this image until it gets modified
+            warn!("Optimization of image {} timed out, it will be served as is", image.image_id);
+            set_passthrough(&cache, &image.image_id);
+
+            return Ok(());
+        }
+        Err(error) => return Err(error),
+    };
+
+    let original_size = fs::metadata(&base_image_path)?.len();
+    if !is_worth_saving(optimized.len() as u64, original_size, config.min_savings_ratio, keep_format) {
+        info!("Optimized image {} in size {} is not small enough, the original will be served", image.image_id, image.size);
+
+        let mut lock = cache.write()?;
+        if let Some(cache) = lock.get_mut(&image.image_id) {
+            cache.skipped.insert((image.size, image.extension));
+        }
+
+        return Ok(());
+    }
+
+    if config.verify_output.unwrap_or(false) {
+        images::verify(&optimized, image.extension.image_format(), width, height)?;
+    }
+
+    images::write(&path, &optimized, None)?;
 
     let mut lock = cache.write().unwrap();
     let cache = lock.get_mut(&image.image_id).unwrap();
@@ -64,3 +169,52 @@ fn save_image(config: Config, cache: CacheData, image: OptimizeImage) -> Result<
 
     Ok(())
 }
+
+//optimized images barely smaller than the original are not worth their cache space, those
+//in the format of the original are never worth it when they are larger
+fn is_worth_saving(optimized_size: u64, original_size: u64, min_savings_ratio: Option<f32>, keep_format: bool) -> bool {
+    let ratio = match min_savings_ratio {
+        Some(ratio) => ratio,
+        None if keep_format => 0.0,
+        None => return true,
+    };
+
+    optimized_size as f32 <= original_size as f32 * (1.0 - ratio)
+}
+
+fn set_passthrough(cache: &CacheData, image_id: &str) {
+    let mut lock = cache.write().unwrap();
+    if let Some(cache) = lock.get_mut(image_id) {
+        cache.passthrough = true;
+    }
+}
+
+fn set_corrupt(cache: &CacheData, image_id: &str, error: &str) -> Result<(), Error> {
+    warn!("Image {} is corrupt, it will not be optimized: {}", image_id, error);
+
+    let mut lock = cache.write()?;
+    if let Some(cache) = lock.get_mut(image_id) {
+        cache.corrupt = true;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn optimized_images_are_saved_when_they_save_enough() {
+        assert!(is_worth_saving(1000, 900, None, false));
+        assert!(is_worth_saving(800, 1000, Some(0.2), false));
+        assert!(!is_worth_saving(801, 1000, Some(0.2), false));
+    }
+
+    #[test]
+    fn images_in_a_kept_format_are_not_saved_when_larger() {
+        assert!(is_worth_saving(1000, 1000, None, true));
+        assert!(!is_worth_saving(1001, 1000, None, true));
+        assert!(!is_worth_saving(900, 1000, Some(0.2), true));
+    }
+}
@@ -1,24 +1,34 @@
+mod accesses;
+mod evictor;
 mod file_saver;
 mod pre_optimizer;
+mod stats;
 mod watcher;
 
 use std::collections::{HashMap, HashSet};
+use std::fs;
 use std::fs::File;
+use std::io::ErrorKind;
 use std::ops::Deref;
 use std::os::unix::fs::MetadataExt;
-use std::path::{Path, PathBuf};
+use std::path::Path;
 use std::sync::{Arc, mpsc, RwLock};
 use std::sync::mpsc::Sender;
 use std::thread;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, SecondsFormat, Utc};
 use headers_accept::Accept;
 use image::ImageFormat;
 use mediatype::MediaType;
-use walkdir::WalkDir;
+use itertools::Itertools;
+use walkdir::{DirEntry, WalkDir};
 use crate::backend::FileTransfer;
+use crate::cache::accesses::Accesses;
 use crate::cache::file_saver::OptimizeImage;
-use crate::config::{Config, Extension};
+use crate::cache::stats::{ImageStats, VariantStats};
+use crate::config::{Config, CorruptFallback, Extension, FormatMismatch, Size, UnsupportedFormats};
 use crate::error::Error;
+use crate::images;
+use crate::pyramid;
 use crate::utils;
 
 pub type CacheData = Arc<RwLock<HashMap<String, CacheImage>>>;
@@ -26,6 +36,7 @@ pub type CacheData = Arc<RwLock<HashMap<String, CacheImage>>>;
 pub struct Cache {
     config: Config,
     data: CacheData,
+    accesses: Accesses,
     create_image_tx: Sender<OptimizeImage>,
 }
 
@@ -33,37 +44,42 @@ impl Cache {
     pub fn new(config: &Config) -> Self {
         let (tx, rx) = mpsc::channel();
         let data = CacheData::default();
+        let accesses = Accesses::default();
 
         let thread_config = config.clone();
         let thread_data = data.clone();
+        let thread_accesses = accesses.clone();
         let thread_tx = tx.clone();
         thread::spawn(move || {
             Self::load_images(&thread_config, thread_data.clone());
             file_saver::spawn(thread_config.clone(), thread_data.clone(), rx);
             watcher::spawn(thread_config.clone(), thread_data.clone(), thread_tx.clone());
             pre_optimizer::spawn(thread_config.clone(), thread_data.clone(), thread_tx.clone());
+            evictor::spawn(thread_config.clone(), thread_data.clone(), thread_accesses);
         });
 
         Cache {
             config: config.clone(),
             data,
+            accesses,
             create_image_tx: tx,
         }
     }
 
     fn load_images(config: &Config, images: CacheData) {
-        let mut lock = images.write().unwrap();
-
         let supported_extensions = ImageFormat::all()
             .flat_map(ImageFormat::extensions_str)
             .map(Deref::deref)
             .collect::<HashSet<&str>>();
 
+        //sidecars come last so that the images they belong to are already known
         let files = config.roots.iter()
-            .flat_map(|root| WalkDir::new(root).into_iter()
-                .filter_map(Result::ok)
+            .flat_map(|root| walk(config, root)
                 .filter(|e| !e.file_type().is_dir())
-                .map(|e| (root.clone(), e)));
+                .map(|e| (root.clone(), e)))
+            .sorted_by_key(|(_, file)| config.is_sidecar(&file.path().to_string_lossy()));
+
+        let mut lock = images.write().unwrap();
 
         for (root, file) in files {
             let filename = file.path().to_string_lossy().to_string();
@@ -74,35 +90,256 @@ impl Cache {
                     continue;
                 }
 
+                let stem = config.image_id(stem);
+                let stem = stem.as_ref();
+
+                if config.is_sidecar(&filename) {
+                    if let Some(item) = lock.get_mut(stem) {
+                        item.add_sidecar(&filename);
+                        continue;
+                    }
+                }
+
                 let mut item = CacheImage::new(filename);
 
                 //load optimized images from cache
-                for size in config.sizes.keys() {
-                    for extension in &config.extensions {
-                        let mut path = PathBuf::from(&config.cache_directory);
-                        path.push(&size);
-                        path.push(stem);
-                        path.set_extension(extension.extensions().first().unwrap());
+                for size in config.sizes.keys().chain(config.save_data_sizes.keys()) {
+                    for extension in config.extensions_for(&item.base_image_path) {
+                        let path = config.cache_path(size, stem, extension);
 
                         if path.exists() {
-                            item.add(size.to_owned(), extension.to_owned(), path);
+                            item.add(size.to_owned(), extension, path);
                         }
                     }
                 }
 
-                lock.insert(stem.to_owned(), item);
+                let path = item.base_image_path.clone();
+                if let Some(previous) = lock.insert(stem.to_owned(), item) {
+                    if config.case_insensitive.unwrap_or(false) {
+                        warn!("Image {} replaced {} which has the same case insensitive path", path, previous.base_image_path);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn contains(&self, image_id: &str) -> Result<bool, Error> {
+        Ok(self.data.read()?.contains_key(self.config.image_id(image_id).as_ref()))
+    }
+
+    //queues the optimization of the missing sizes and formats of the given images,
+    //returns the number of optimizations queued
+    pub fn prewarm(&self, image_ids: &[&str]) -> Result<usize, Error> {
+        let lock = self.data.read()?;
+        let mut queued = 0;
+
+        for image_id in image_ids {
+            let image_id = self.config.image_id(image_id);
+            let Some(cache) = lock.get(image_id.as_ref()) else {
+                warn!("Unable to prewarm unknown image {}", image_id);
+                continue;
+            };
+
+            if cache.passthrough || cache.unsupported || cache.corrupt {
+                continue;
+            }
+
+            for size in self.config.sizes.iter().filter(|(_, size)| size.matches(&image_id)).map(|(name, _)| name) {
+                for extension in self.config.extensions_for(&cache.base_image_path) {
+                    if !cache.needs(size, extension) {
+                        continue;
+                    }
+
+                    let sent = self.create_image_tx.send(OptimizeImage {
+                        image_id: image_id.to_string(),
+                        size: size.to_owned(),
+                        extension,
+                    });
+
+                    if sent.is_ok() {
+                        queued += 1;
+                    }
+                }
+            }
+        }
+
+        Ok(queued)
+    }
+
+    //removes the optimized images of the given size, or of every size, from the
+    //cache so that they get optimized again, returns the number of images removed
+    pub fn purge(&self, image_ids: &[&str], size: Option<&str>) -> Result<usize, Error> {
+        let mut to_delete = Vec::new();
+
+        //the save-data variant of a size is purged along with it
+        let sizes = size.map(|size| [size.to_owned(), Config::save_data_size_name(size)]);
+        let is_kept = |other: &str| sizes.as_ref().is_some_and(|sizes| !sizes.iter().any(|size| size == other));
+
+        {
+            let mut lock = self.data.write()?;
+
+            for image_id in image_ids {
+                let Some(cache) = lock.get_mut(self.config.image_id(image_id).as_ref()) else {
+                    warn!("Unable to purge unknown image {}", image_id);
+                    continue;
+                };
+
+                cache.passthrough = false;
+                cache.skipped.retain(|(skipped_size, _)| is_kept(skipped_size));
+                cache.optimized.retain(|(optimized_size, _), path| {
+                    if is_kept(optimized_size) {
+                        return true;
+                    }
+
+                    to_delete.push(path.clone());
+                    false
+                });
+            }
+        }
+
+        for path in &to_delete {
+            self.accesses.remove(path)?;
+
+            if let Err(error) = fs::remove_file(path) {
+                if error.kind() != ErrorKind::NotFound {
+                    return Err(error.into());
+                }
+            }
+        }
+
+        Ok(to_delete.len())
+    }
+
+    //removes every optimized image, the directories of the sizes are moved away before being
+    //deleted so that the files still being transferred can be read until their end. Only the
+    //sizes whose directory got moved are forgotten, the files of the others are still tracked
+    pub fn clear(&self) -> Result<usize, Error> {
+        let mut lock = self.data.write()?;
+
+        let directory = Path::new(&self.config.cache_directory);
+        let timestamp = Utc::now().timestamp_nanos_opt().unwrap_or(0);
+        let mut cleared = Vec::new();
+        let mut cleared_sizes = HashSet::new();
+        let mut errors = Vec::new();
+
+        for size in self.config.sizes.keys().chain(self.config.save_data_sizes.keys()) {
+            let size_directory = directory.join(size);
+            if !size_directory.exists() {
+                cleared_sizes.insert(size.as_str());
+                continue;
+            }
+
+            let cleared_directory = directory.join(format!(".cleared-{}-{}", size, timestamp));
+            match fs::rename(&size_directory, &cleared_directory) {
+                Ok(()) => {
+                    cleared.push(cleared_directory);
+                    cleared_sizes.insert(size.as_str());
+                }
+                Err(error) => errors.push(format!("{}: {}", size_directory.display(), error)),
             }
         }
+
+        let mut removed = 0;
+        for cache in lock.values_mut() {
+            cache.passthrough = false;
+            cache.skipped.retain(|(size, _)| !cleared_sizes.contains(size.as_str()));
+            cache.optimized.retain(|(size, _), path| {
+                if !cleared_sizes.contains(size.as_str()) {
+                    return true;
+                }
+
+                //recreated images start over with no accesses
+                let _ = self.accesses.remove(path);
+                removed += 1;
+                false
+            });
+        }
+
+        drop(lock);
+
+        if !cleared.is_empty() {
+            thread::spawn(move || {
+                for directory in cleared {
+                    if let Err(error) = fs::remove_dir_all(&directory) {
+                        error!("Failed to remove cleared cache directory {}: {}", directory.display(), error);
+                    }
+                }
+            });
+        }
+
+        if !errors.is_empty() {
+            return Error::err(format!("Unable to clear the cache directories {}", errors.join(", ")));
+        }
+
+        Ok(removed)
+    }
+
+    //statistics of the optimized images of an image serialized as json
+    pub fn stats(&self, image_id: &str) -> Result<Option<String>, Error> {
+        let image_id = self.config.image_id(image_id);
+        let lock = self.data.read()?;
+        let Some(cache) = lock.get(image_id.as_ref()) else {
+            return Ok(None);
+        };
+
+        let mut variants = Vec::new();
+        for ((size, extension), path) in cache.optimized.iter().sorted() {
+            let access = self.accesses.get(path)?;
+
+            variants.push(VariantStats {
+                size: size.to_owned(),
+                format: extension.extensions().first().unwrap_or(&"").to_string(),
+                bytes: fs::metadata(path).map_or(0, |metadata| metadata.len()),
+                hits: access.map_or(0, |(_, hits)| hits),
+                last_access: access.map(|(time, _)| time.to_rfc3339_opts(SecondsFormat::Secs, true)),
+            });
+        }
+
+        Ok(Some(serde_json::to_string(&ImageStats::new(&image_id, variants))?))
     }
 
-    pub fn get(&self, image_id: &str, size: &str, accept: Option<Accept>) -> Result<Option<FetchResult>, Error> {
+    pub fn get(&self, image_id: &str, size: &str, accept: Option<Accept>, with_body: bool) -> Result<Option<FetchResult>, Error> {
+        let image_id = self.config.image_id(image_id);
+        let image_id = image_id.as_ref();
         let lock = self.data.read()?;
         let Some(cache) = lock.get(image_id) else {
             return Ok(None);
         };
 
+        //images that can not be optimized, like animations, are served as is
+        if cache.passthrough {
+            return self.read_image(&cache.base_image_path, cache.content_format, false, with_body);
+        }
+
+        //images that can not be decoded are either served as is or rejected
+        if cache.unsupported {
+            return match self.config.unsupported_formats.unwrap_or(UnsupportedFormats::Passthrough) {
+                UnsupportedFormats::Passthrough => self.read_image(&cache.base_image_path, cache.content_format, false, with_body),
+                UnsupportedFormats::Reject => Err(Error::UnsupportedMediaType),
+            };
+        }
+
+        //images whose content failed to decode are never optimized until they get modified
+        if cache.corrupt {
+            return match &self.config.corrupt_fallback {
+                None | Some(CorruptFallback::Passthrough) => self.read_image(&cache.base_image_path, cache.content_format, false, with_body),
+                Some(CorruptFallback::Placeholder(path)) => self.read_image(path, None, false, with_body),
+                Some(CorruptFallback::Reject) => Err(Error::UnsupportedMediaType),
+            };
+        }
+
+        if self.config.webp_passthrough.unwrap_or(false) && self.is_webp_passthrough(cache, size, accept.as_ref()) {
+            return self.read_image(&cache.base_image_path, cache.content_format, false, with_body);
+        }
+
+        //prebuilt alternative formats accepted by the client are served without optimizing the image
+        if let Some(sidecar) = self.accepted_sidecar(cache, size, accept.as_ref()) {
+            return self.read_image(sidecar, None, false, with_body);
+        }
+
         //convert unavailable extensions
-        for extension in self.config.extensions.iter().filter(|ext| !cache.has(size, **ext)) {
+        let extensions = self.config.extensions_for(&cache.base_image_path);
+        for extension in extensions.iter().filter(|ext| cache.needs(size, **ext)) {
             let _ = self.create_image_tx.send(OptimizeImage {
                 image_id: image_id.to_owned(),
                 size: size.to_owned(),
@@ -110,21 +347,30 @@ impl Cache {
             });
         }
 
-        let converted_extensions = self.config.extensions.iter()
-            .filter(|ext| cache.has(size, **ext))
-            .map(|ext| ext.to_media_type())
-            .collect::<Vec<MediaType>>();
+        let appropriate_extension = if self.config.keep_format.unwrap_or(false) {
+            //images in formats that can not be optimized are served as is
+            let Some(source_extension) = extensions.first() else {
+                return self.read_image(&cache.base_image_path, cache.content_format, false, with_body);
+            };
 
-        let appropriate_extension = accept.as_ref()
-            .and_then(|accept| accept.negotiate(converted_extensions.iter()))
-            .and_then(|media_type| Extension::from_ext(media_type.subty.as_str()))
-            .unwrap_or(self.config.default_format);
+            *source_extension
+        } else {
+            let converted_extensions = extensions.iter()
+                .filter(|ext| cache.has(size, **ext))
+                .map(|ext| ext.to_media_type())
+                .collect::<Vec<MediaType>>();
+
+            accept.as_ref()
+                .and_then(|accept| accept.negotiate(converted_extensions.iter()))
+                .and_then(|media_type| Extension::from_ext(media_type.subty.as_str()))
+                .unwrap_or(self.config.default_format)
+        };
 
         if let Some(file) = cache.get(size, appropriate_extension) {
             let path = Path::new(file);
 
             if path.exists() {
-                return self.read_image(file, true);
+                return self.read_image(file, None, true, with_body);
             } else {
                 //the image was in cache but the file did not exist,
                 //maybe it got deleted
@@ -136,17 +382,87 @@ impl Cache {
             }
         }
 
+        //serve the closest precomputed resolution while the optimized image is generated
+        let levels = pyramid::levels(&self.config, &cache.base_image_path);
+        if let Some(level) = self.config.size(size).and_then(|size| pyramid::closest(&levels, size)) {
+            return self.read_image(&level.path, None, false, with_body);
+        }
+
         //return the image as is, it will be optimized later
-        self.read_image(&cache.base_image_path, false)
+        self.read_image(&cache.base_image_path, cache.content_format, false, with_body)
+    }
+
+    pub fn get_original(&self, image_id: &str, with_body: bool) -> Result<Option<FetchResult>, Error> {
+        let lock = self.data.read()?;
+        let Some(cache) = lock.get(self.config.image_id(image_id).as_ref()) else {
+            return Ok(None);
+        };
+
+        self.read_image(&cache.base_image_path, cache.content_format, false, with_body)
+    }
+
+    //webp images that would not be resized can be sent to clients accepting
+    //webp without decoding and encoding them again
+    fn is_webp_passthrough(&self, cache: &CacheImage, size: &str, accept: Option<&Accept>) -> bool {
+        if Extension::from_path(&cache.base_image_path) != Some(Extension::WEBP) {
+            return false;
+        }
+
+        //the format of the images is not negotiated when it is kept
+        let webp = [Extension::WEBP.to_media_type()];
+        let accepts_webp = accept.is_some_and(|accept| accept.negotiate(webp.iter()).is_some());
+        if !accepts_webp && !self.config.keep_format.unwrap_or(false) {
+            return false;
+        }
+
+        let Some(size) = self.config.size(size).filter(|size| !size.upscale.unwrap_or(false)) else {
+            return false;
+        };
+
+        cache.dimensions.is_some_and(|(width, height)| width <= size.width && height <= size.height)
+    }
+
+    //the best sidecar accepted by the client, sidecars are in other formats than the
+    //source so they are not served when it is kept
+    fn accepted_sidecar<'a>(&self, cache: &'a CacheImage, size: &str, accept: Option<&Accept>) -> Option<&'a String> {
+        let size = self.config.size(size).filter(|_| !self.config.keep_format.unwrap_or(false))?;
+
+        let sidecar_extensions = cache.sidecars.keys()
+            .filter(|ext| cache.sidecar(size, **ext).is_some())
+            .map(|ext| ext.to_media_type())
+            .collect::<Vec<MediaType>>();
+
+        accept
+            .and_then(|accept| accept.negotiate(sidecar_extensions.iter()))
+            .and_then(|media_type| Extension::from_ext(media_type.subty.as_str()))
+            .and_then(|extension| cache.sidecar(size, extension))
     }
 
-    fn read_image(&self, path: &str, is_optimized: bool) -> Result<Option<FetchResult>, Error> {
-        let file = File::open(path)?;
-        let metadata = file.metadata()?;
-        let format = ImageFormat::from_path(path)?;
+    //responses without a body are answered from the metadata of the file without opening
+    //it, the content of the original images is sniffed once when they get indexed
+    fn read_image(&self, path: &str, content_format: Option<ImageFormat>, is_optimized: bool, with_body: bool) -> Result<Option<FetchResult>, Error> {
+        let (file, metadata, sniffed) = if with_body {
+            let mut file = File::open(path)?;
+            let metadata = file.metadata()?;
+            let sniffed = images::sniff_format(&mut file)?;
+
+            (Some(file), metadata, sniffed)
+        } else {
+            (None, fs::metadata(path)?, content_format)
+        };
+
+        //files may have an extension that does not match their content
+        let policy = self.config.format_mismatch.unwrap_or(FormatMismatch::TrustContent);
+        let format = images::resolve_format(ImageFormat::from_path(path)?, sniffed, policy)?;
+
+        if is_optimized && with_body {
+            self.accesses.record(path)?;
+        }
 
         Ok(Some(FetchResult {
-            data: FileTransfer::new(file, metadata.len()),
+            data: file.map(|file| FileTransfer::new(file, metadata.len())),
+            size: metadata.len(),
+            path: path.to_owned(),
             last_modified: DateTime::from(metadata.modified() ? ),
             inode: metadata.ino(),
             mime: format.to_mime_type(),
@@ -158,21 +474,66 @@ impl Cache {
 #[derive(Clone, Debug)]
 pub struct CacheImage {
     pub base_image_path: String,
+    pub passthrough: bool,
+    pub unsupported: bool,
+    pub corrupt: bool,
+    pub dimensions: Option<(u32, u32)>, //read once for the webp passthrough and the sidecars
+    pub content_format: Option<ImageFormat>, //sniffed once for the responses without a body
+    pub sidecars: HashMap<Extension, String>,
     pub optimized: HashMap<(String, Extension), String>, //associating size and extension to the path
+    pub skipped: HashSet<(String, Extension)>, //sizes and extensions not worth optimizing
 }
 
 impl CacheImage {
     pub fn new(base_image_path: String) -> Self {
-        CacheImage {
+        let mut image = CacheImage {
+            unsupported: !images::can_decode(&base_image_path),
+            dimensions: None,
+            content_format: None,
             base_image_path,
+            passthrough: false,
+            corrupt: false,
+            sidecars: HashMap::new(),
             optimized: HashMap::new(),
-        }
+            skipped: HashSet::new(),
+        };
+
+        image.refresh();
+        image
+    }
+
+    //the image got modified, its dimensions and format may have changed
+    pub fn refresh(&mut self) {
+        let is_webp = Extension::from_path(&self.base_image_path) == Some(Extension::WEBP);
+        self.dimensions = if is_webp || !self.sidecars.is_empty() {
+            image::image_dimensions(&self.base_image_path).ok()
+        } else {
+            None
+        };
+        self.content_format = content_format(&self.base_image_path);
     }
 
     pub fn add<P: AsRef<Path>>(&mut self, size: String, ext: Extension, path: P) {
         self.optimized.insert((size, ext), path.as_ref().to_string_lossy().to_string());
     }
 
+    pub fn add_sidecar(&mut self, path: &str) {
+        if let Some(extension) = Extension::from_path(path) {
+            self.sidecars.insert(extension, path.to_owned());
+        }
+
+        if self.dimensions.is_none() {
+            self.dimensions = image::image_dimensions(&self.base_image_path).ok();
+        }
+    }
+
+    //sidecars have the dimensions of the image, they can only replace
+    //it in the sizes where it does not get resized
+    pub fn sidecar(&self, size: &Size, ext: Extension) -> Option<&String> {
+        let fits = !size.upscale.unwrap_or(false) && self.dimensions.is_some_and(|(width, height)| width <= size.width && height <= size.height);
+        self.sidecars.get(&ext).filter(|_| fits)
+    }
+
     pub fn get(&self, size: &str, ext: Extension) -> Option<&String> {
         self.optimized.get(&(size.to_string(), ext))
     }
@@ -180,13 +541,257 @@ impl CacheImage {
     pub fn has(&self, size: &str, ext: Extension) -> bool {
         self.optimized.contains_key(&(size.to_string(), ext))
     }
+
+    //whether the image has to be optimized in this size and format
+    pub fn needs(&self, size: &str, ext: Extension) -> bool {
+        !self.has(size, ext) && !self.skipped.contains(&(size.to_string(), ext))
+    }
 }
 
+//walks the files and directories of a root without entering the symlinks that are not allowed
+fn walk<'a>(config: &'a Config, root: &str) -> impl Iterator<Item = DirEntry> + 'a {
+    WalkDir::new(root).follow_links(true).into_iter()
+        .filter_entry(|e| {
+            let allowed = config.is_allowed_symlink(e.path());
+            if !allowed {
+                warn!("{} links outside of the allowed directories, it will not be served", e.path().display());
+            }
+
+            allowed
+        })
+        .filter_map(Result::ok)
+}
+
+fn content_format(path: &str) -> Option<ImageFormat> {
+    let mut file = File::open(path).ok()?;
+    images::sniff_format(&mut file).ok().flatten()
+}
 
 pub struct FetchResult {
-    pub data: FileTransfer,
+    pub data: Option<FileTransfer>, //only opened for the responses with a body
+    pub size: u64,
+    pub path: String,
     pub last_modified: DateTime<Utc>,
     pub inode: u64,
     pub mime: &'static str,
     pub is_optimized: bool,
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::str::FromStr;
+    use std::sync::mpsc::Receiver;
+    use image::DynamicImage;
+    use super::*;
+
+    //caches built without their background threads, the
+    //optimizations they queue are received by the test
+    fn cache(config: Config) -> (Cache, Receiver<OptimizeImage>) {
+        let (tx, rx) = mpsc::channel();
+        let cache = Cache {
+            config,
+            data: CacheData::default(),
+            accesses: Accesses::default(),
+            create_image_tx: tx,
+        };
+
+        (cache, rx)
+    }
+
+    fn directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("impress_{}", name));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+
+        directory
+    }
+
+    fn insert(cache: &Cache, image_id: &str, path: &Path) {
+        let image = CacheImage::new(path.to_string_lossy().to_string());
+        cache.data.write().unwrap().insert(image_id.to_owned(), image);
+    }
+
+    #[test]
+    fn mismatched_images_are_served_according_to_the_policy() {
+        let directory = directory("format_mismatch");
+        let path = directory.join("photo.jpg");
+        DynamicImage::new_rgb8(4, 4).save_with_format(&path, ImageFormat::Png).unwrap();
+
+        let served = |policy| {
+            let (cache, _rx) = cache(Config { format_mismatch: Some(policy), ..Config::default() });
+            insert(&cache, "photo", &path);
+
+            cache.get("photo", "default", None, true).map(|result| result.unwrap().mime)
+        };
+
+        assert_eq!(served(FormatMismatch::TrustContent).unwrap(), "image/png");
+        assert_eq!(served(FormatMismatch::TrustExtension).unwrap(), "image/jpeg");
+        assert!(matches!(served(FormatMismatch::Reject), Err(Error::UnsupportedMediaType)));
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn responses_without_a_body_do_not_open_the_image() {
+        let directory = directory("without_body");
+        let path = directory.join("photo.jpg");
+        DynamicImage::new_rgb8(4, 4).save_with_format(&path, ImageFormat::Png).unwrap();
+
+        let (cache, _rx) = cache(Config::default());
+        insert(&cache, "photo", &path);
+
+        let result = cache.get("photo", "default", None, false).unwrap().unwrap();
+        assert!(result.data.is_none());
+        assert_eq!(result.size, fs::metadata(&path).unwrap().len());
+        assert_eq!(result.mime, "image/png");
+
+        let result = cache.get_original("photo", false).unwrap().unwrap();
+        assert!(result.data.is_none());
+        assert_eq!(result.size, fs::metadata(&path).unwrap().len());
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn disallowed_symlinks_are_not_indexed() {
+        let directory = directory("load_symlinks");
+        for name in ["root", "allowed", "outside"] {
+            fs::create_dir_all(directory.join(name)).unwrap();
+        }
+
+        let image = DynamicImage::new_rgb8(4, 4);
+        image.save(directory.join("root/photo.png")).unwrap();
+        image.save(directory.join("allowed/photo.png")).unwrap();
+        image.save(directory.join("outside/photo.png")).unwrap();
+        std::os::unix::fs::symlink(directory.join("allowed"), directory.join("root/allowed")).unwrap();
+        std::os::unix::fs::symlink(directory.join("outside"), directory.join("root/outside")).unwrap();
+
+        let (cache, _rx) = cache(Config {
+            roots: vec![directory.join("root").to_string_lossy().to_string()],
+            symlink_targets: Some(vec![directory.join("allowed").to_string_lossy().to_string()]),
+            ..Config::default()
+        });
+        Cache::load_images(&cache.config, cache.data.clone());
+
+        assert!(cache.contains("photo").unwrap());
+        assert!(cache.contains("allowed/photo").unwrap());
+        assert!(!cache.contains("outside/photo").unwrap());
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn kept_formats_are_optimized_without_negotiation() {
+        let directory = directory("keep_format");
+        let (cache, rx) = cache(Config { keep_format: Some(true), ..Config::default() });
+        let accept = || Some(Accept::from_str("image/avif").unwrap());
+
+        let sources = [("jpeg", ImageFormat::Jpeg, Some(Extension::JPEG)), ("webp", ImageFormat::WebP, Some(Extension::WEBP)), ("png", ImageFormat::Png, None)];
+        for (name, format, optimized) in sources {
+            let path = directory.join(format!("photo.{}", name));
+            DynamicImage::new_rgb8(4, 4).save_with_format(&path, format).unwrap();
+            insert(&cache, name, &path);
+
+            let result = cache.get(name, "default", accept(), true).unwrap().unwrap();
+            assert_eq!(result.mime, format.to_mime_type());
+            assert_eq!(rx.try_iter().map(|queued| queued.extension).collect::<Vec<_>>(), optimized.into_iter().collect::<Vec<_>>());
+        }
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn sidecars_are_selected_by_accept() {
+        let directory = directory("sidecars");
+        let path = directory.join("photo.png");
+        DynamicImage::new_rgb8(4, 4).save(&path).unwrap();
+        DynamicImage::new_rgb8(4, 4).save(directory.join("photo.webp")).unwrap();
+        fs::write(directory.join("photo.avif"), "").unwrap();
+
+        let (cache, rx) = cache(Config { sidecars: Some(true), ..Config::default() });
+        let mut image = CacheImage::new(path.to_string_lossy().to_string());
+        image.add_sidecar(&directory.join("photo.webp").to_string_lossy());
+        image.add_sidecar(&directory.join("photo.avif").to_string_lossy());
+        cache.data.write().unwrap().insert(String::from("photo"), image);
+
+        let served = |accept: &str| {
+            let accept = Accept::from_str(accept).ok();
+            let result = cache.get("photo", "default", accept, true).unwrap().unwrap();
+
+            (result.mime, rx.try_iter().count())
+        };
+
+        assert_eq!(served("image/webp"), ("image/webp", 0));
+        assert_eq!(served("image/avif, image/webp;q=0.9"), ("image/avif", 0));
+        assert_eq!(served("image/webp, image/avif;q=0.9"), ("image/webp", 0));
+        assert_eq!(served("image/jpeg").0, "image/png");
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn sidecars_are_not_served_in_sizes_resizing_the_image() {
+        let directory = directory("resized_sidecars");
+        let path = directory.join("photo.png");
+        DynamicImage::new_rgb8(600, 4).save(&path).unwrap();
+        DynamicImage::new_rgb8(600, 4).save(directory.join("photo.webp")).unwrap();
+
+        let (cache, rx) = cache(Config { sidecars: Some(true), ..Config::default() });
+        let mut image = CacheImage::new(path.to_string_lossy().to_string());
+        image.add_sidecar(&directory.join("photo.webp").to_string_lossy());
+        cache.data.write().unwrap().insert(String::from("photo"), image);
+
+        let result = cache.get("photo", "default", Accept::from_str("image/webp").ok(), true).unwrap().unwrap();
+        assert_eq!(result.mime, "image/png");
+        assert!(rx.try_iter().count() > 0);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    //an image optimized in the default size that got served once
+    fn optimized_image(cache: &Cache, directory: &Path) -> PathBuf {
+        let path = directory.join("photo.png");
+        DynamicImage::new_rgb8(4, 4).save(&path).unwrap();
+        insert(cache, "photo", &path);
+
+        let optimized = cache.config.cache_path("default", "photo", Extension::AVIF);
+        fs::create_dir_all(optimized.parent().unwrap()).unwrap();
+        fs::write(&optimized, "").unwrap();
+        cache.data.write().unwrap().get_mut("photo").unwrap().add(String::from("default"), Extension::AVIF, &optimized);
+        cache.accesses.record(&optimized.to_string_lossy()).unwrap();
+
+        optimized
+    }
+
+    #[test]
+    fn cleared_images_are_forgotten_and_optimized_again() {
+        let directory = directory("clear");
+        let (cache, rx) = cache(Config { cache_directory: directory.join("cache").to_string_lossy().to_string(), ..Config::default() });
+        let optimized = optimized_image(&cache, &directory);
+
+        assert_eq!(cache.clear().unwrap(), 1);
+        assert!(!directory.join("cache/default").exists());
+        assert!(cache.data.read().unwrap()["photo"].optimized.is_empty());
+        assert!(cache.accesses.get(&optimized.to_string_lossy()).unwrap().is_none());
+
+        cache.get("photo", "default", None, true).unwrap();
+        assert_eq!(rx.try_iter().map(|queued| queued.extension).collect::<Vec<_>>(), [Extension::AVIF]);
+
+        //the cleared directory may still be being deleted
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn purged_images_are_forgotten() {
+        let directory = directory("purge");
+        let (cache, _rx) = cache(Config { cache_directory: directory.join("cache").to_string_lossy().to_string(), ..Config::default() });
+        let optimized = optimized_image(&cache, &directory);
+
+        assert_eq!(cache.purge(&["photo"], None).unwrap(), 1);
+        assert!(!optimized.exists());
+        assert!(cache.accesses.get(&optimized.to_string_lossy()).unwrap().is_none());
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,27 @@
+use serde::Serialize;
+
+#[derive(Serialize, Debug)]
+pub struct ImageStats {
+    pub image: String,
+    pub variants: Vec<VariantStats>,
+    pub total_size: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct VariantStats {
+    pub size: String,
+    pub format: String,
+    pub bytes: u64,
+    pub hits: u64,
+    pub last_access: Option<String>, //not served since varnish started when empty
+}
+
+impl ImageStats {
+    pub fn new(image: &str, variants: Vec<VariantStats>) -> ImageStats {
+        ImageStats {
+            image: image.to_owned(),
+            total_size: variants.iter().map(|variant| variant.bytes).sum(),
+            variants,
+        }
+    }
+}
@@ -5,7 +5,7 @@ use std::collections::HashMap;
 use itertools::Itertools;
 use notify::{Config as NotifyConfig, Error as NotifyError, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
 use notify::event::{AccessKind, AccessMode, ModifyKind, RemoveKind, RenameMode};
-use crate::cache::{CacheData, CacheImage};
+use crate::cache::{walk, CacheData, CacheImage};
 use crate::cache::file_saver::OptimizeImage;
 use crate::config::Config;
 use crate::error::Error;
@@ -19,19 +19,38 @@ pub fn spawn(config: Config, data: CacheData, create_image_tx: Sender<OptimizeIm
             watcher.watch(Path::new(root), RecursiveMode::Recursive).unwrap();
         }
 
-        event_handler(config, data, rx, create_image_tx);
+        //not every platform follows symlinks when watching recursively, so the directories
+        //they link to outside of the roots are watched too
+        let links = symlinked_directories(&config);
+        for (link, target) in &links {
+            if let Err(e) = watcher.watch(target, RecursiveMode::Recursive) {
+                error!("could not watch {} linked by {}: {:?}", target.display(), link.display(), e);
+            }
+        }
+
+        event_handler(config, data, links, rx, create_image_tx);
     });
 }
 
-fn event_handler(config: Config, data: CacheData, rx: Receiver<Result<Event, NotifyError>>, create_image_tx: Sender<OptimizeImage>) {
+//the allowed symlinks to directories outside of the roots along with their targets
+fn symlinked_directories(config: &Config) -> Vec<(PathBuf, PathBuf)> {
+    config.roots.iter()
+        .flat_map(|root| walk(config, root))
+        .filter(|entry| entry.path_is_symlink() && entry.file_type().is_dir())
+        .filter_map(|entry| Some((entry.path().to_path_buf(), fs::canonicalize(entry.path()).ok()?)))
+        .filter(|(_, target)| !config.is_inside_roots(target))
+        .collect()
+}
+
+fn event_handler(config: Config, data: CacheData, links: Vec<(PathBuf, PathBuf)>, rx: Receiver<Result<Event, NotifyError>>, create_image_tx: Sender<OptimizeImage>) {
     while let Ok(result) = rx.recv() {
         match result {
             Ok(event) => {
                 let result = match event.kind {
-                    EventKind::Access(AccessKind::Close(AccessMode::Write)) => handle_modification(event, &config, &data, create_image_tx.clone()),
-                    EventKind::Remove(RemoveKind::File) => handle_deletion(event, &config, &data),
-                    EventKind::Modify(ModifyKind::Name(RenameMode::From)) => handle_deletion(event, &config, &data),
-                    EventKind::Modify(ModifyKind::Name(RenameMode::To)) => handle_modification(event, &config, &data, create_image_tx.clone()),
+                    EventKind::Access(AccessKind::Close(AccessMode::Write)) => handle_modification(event, &config, &data, &links, create_image_tx.clone()),
+                    EventKind::Remove(RemoveKind::File) => handle_deletion(event, &config, &data, &links),
+                    EventKind::Modify(ModifyKind::Name(RenameMode::From)) => handle_deletion(event, &config, &data, &links),
+                    EventKind::Modify(ModifyKind::Name(RenameMode::To)) => handle_modification(event, &config, &data, &links, create_image_tx.clone()),
                     _ => Ok(()),
                 };
 
@@ -44,18 +63,34 @@ fn event_handler(config: Config, data: CacheData, rx: Receiver<Result<Event, Not
     }
 }
 
-fn handle_modification(event: Event, config: &Config, data: &CacheData, create_image_tx: Sender<OptimizeImage>) -> Result<(), Error> {
-    let image_path = get_image_path(&event)?;
+fn handle_modification(event: Event, config: &Config, data: &CacheData, links: &[(PathBuf, PathBuf)], create_image_tx: Sender<OptimizeImage>) -> Result<(), Error> {
+    let image_path = get_image_path(&event, links)?;
     let image_id = get_image_id(&image_path, &config);
 
+    if !config.is_allowed_symlink(Path::new(&image_path)) {
+        warn!("Image {} links outside of the allowed directories, it will not be served", image_path);
+        return Ok(());
+    }
+
     let to_delete = {
         let mut lock = data.write().unwrap();
 
+        if config.is_sidecar(&image_path) {
+            if let Some(cache) = lock.get_mut(&image_id).filter(|cache| cache.base_image_path != image_path) {
+                cache.add_sidecar(&image_path);
+                return Ok(());
+            }
+        }
+
         if !lock.contains_key(&image_id) {
             lock.insert(image_id.to_string(), CacheImage::new(image_path.to_owned()));
         }
 
         if let Some(cache) = lock.get_mut(&image_id) {
+            cache.passthrough = false;
+            cache.corrupt = false;
+            cache.refresh();
+            cache.skipped.clear();
             mem::take(&mut cache.optimized)
         } else {
             HashMap::new()
@@ -68,9 +103,9 @@ fn handle_modification(event: Event, config: &Config, data: &CacheData, create_i
 
     let to_optimize = config.sizes.iter()
         .filter(|(_, size)| size.matches(&image_id) && size.pre_optimize.unwrap_or(false))
-        .cartesian_product(config.extensions.iter());
+        .cartesian_product(config.extensions_for(&image_path));
 
-    for ((size_name, _), &format) in to_optimize {
+    for ((size_name, _), format) in to_optimize {
         create_image_tx.send(OptimizeImage {
             image_id: image_id.clone(),
             size: size_name.clone(),
@@ -81,11 +116,21 @@ fn handle_modification(event: Event, config: &Config, data: &CacheData, create_i
     Ok(())
 }
 
-fn handle_deletion(event: Event, config: &Config, data: &CacheData) -> Result<(), Error> {
-    let image_path = get_image_path(&event)?;
+fn handle_deletion(event: Event, config: &Config, data: &CacheData, links: &[(PathBuf, PathBuf)]) -> Result<(), Error> {
+    let image_path = get_image_path(&event, links)?;
     let image_id = get_image_id(&image_path, &config);
 
-    let image = data.write().unwrap().remove(&image_id);
+    let image = {
+        let mut lock = data.write().unwrap();
+
+        match lock.get_mut(&image_id) {
+            Some(cache) if cache.base_image_path != image_path => {
+                cache.sidecars.retain(|_, sidecar| *sidecar != image_path);
+                None
+            }
+            _ => lock.remove(&image_id),
+        }
+    };
 
     if let Some(image) = image {
         for (_, path) in image.optimized {
@@ -96,20 +141,42 @@ fn handle_deletion(event: Event, config: &Config, data: &CacheData) -> Result<()
     Ok(())
 }
 
-fn get_image_path(event: &Event) -> Result<String, Error> {
+fn get_image_path(event: &Event, links: &[(PathBuf, PathBuf)]) -> Result<String, Error> {
     if let Some(path) = event.paths.first() {
-        Ok(path.to_string_lossy().to_string())
+        Ok(linked_path(path, links).to_string_lossy().to_string())
     } else {
         Error::err("No path in event")
     }
 }
 
+//images in linked directories are known by their path through the symlink
+fn linked_path(path: &Path, links: &[(PathBuf, PathBuf)]) -> PathBuf {
+    links.iter()
+        .find_map(|(link, target)| path.strip_prefix(target).ok().map(|rest| link.join(rest)))
+        .unwrap_or_else(|| path.to_path_buf())
+}
+
 fn get_image_id(path: &str, config: &Config) -> String {
     let mut image_id = PathBuf::from(path);
     image_id.set_extension("");
 
-   config.roots.iter()
+   let image_id = config.roots.iter()
        .fold(image_id.as_path(), |acc, root| acc.strip_prefix(root).unwrap_or(acc))
-       .to_string_lossy()
-       .to_string()
+       .to_string_lossy();
+
+   config.image_id(&image_id).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_in_linked_directories_are_translated_to_the_link() {
+        let links = vec![(PathBuf::from("/srv/images/shared"), PathBuf::from("/mnt/shared"))];
+
+        assert_eq!(linked_path(Path::new("/mnt/shared/a/photo.jpg"), &links), Path::new("/srv/images/shared/a/photo.jpg"));
+        assert_eq!(linked_path(Path::new("/srv/images/photo.jpg"), &links), Path::new("/srv/images/photo.jpg"));
+        assert_eq!(linked_path(Path::new("/mnt/shared-other/photo.jpg"), &links), Path::new("/mnt/shared-other/photo.jpg"));
+    }
 }
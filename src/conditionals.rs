@@ -0,0 +1,208 @@
+use chrono::{DateTime, Utc};
+use varnish::vcl::http::HTTP;
+use crate::utils;
+
+//conditional headers of a request
+#[derive(Default, Debug)]
+pub struct Conditionals<'a> {
+    pub if_match: Option<&'a str>,
+    pub if_unmodified_since: Option<&'a str>,
+    pub if_none_match: Option<&'a str>,
+    pub if_modified_since: Option<&'a str>,
+    pub if_range: Option<&'a str>,
+}
+
+impl<'a> Conditionals<'a> {
+    pub fn from_headers(headers: &'a HTTP) -> Conditionals<'a> {
+        Conditionals {
+            if_match: headers.header("if-match"),
+            if_unmodified_since: headers.header("if-unmodified-since"),
+            if_none_match: headers.header("if-none-match"),
+            if_modified_since: headers.header("if-modified-since"),
+            if_range: headers.header("if-range"),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum Precondition {
+    NotModified,
+    Failed,
+    Proceed,
+}
+
+//evaluates the conditional headers in the order of rfc 9110, the date
+//based headers are ignored when their etag counterpart is present
+pub fn evaluate_preconditions(conditionals: &Conditionals, etag: &str, weak_etag: bool, last_modified: &DateTime<Utc>) -> Precondition {
+    if let Some(im) = conditionals.if_match {
+        if !matches_etags(im, etag, weak_etag, false) {
+            return Precondition::Failed;
+        }
+    } else if let Some(ius) = conditionals.if_unmodified_since {
+        if let Some(t) = utils::parse_http_date(ius) {
+            if last_modified.timestamp() > t.timestamp() {
+                return Precondition::Failed;
+            }
+        }
+    }
+
+    if let Some(inm) = conditionals.if_none_match {
+        if matches_etags(inm, etag, weak_etag, true) {
+            return Precondition::NotModified;
+        }
+    } else if let Some(ims) = conditionals.if_modified_since {
+        if let Some(t) = utils::parse_http_date(ims) {
+            //http dates, like the etags, only have a precision of one second
+            if last_modified.timestamp() <= t.timestamp() {
+                return Precondition::NotModified;
+            }
+        }
+    }
+
+    Precondition::Proceed
+}
+
+//if-range requires a strong validator, weak etags never match
+pub fn matches_if_range(conditionals: &Conditionals, etag: &str, weak_etag: bool, last_modified: &DateTime<Utc>) -> bool {
+    match conditionals.if_range {
+        Some(if_range) => match utils::parse_http_date(if_range) {
+            Some(t) => t.timestamp() == last_modified.timestamp(),
+            None => !weak_etag && if_range.trim_matches('"') == etag,
+        },
+        None => true,
+    }
+}
+
+//matches an etag against a comma separated list of etags, weak
+//etags can only match when using the weak comparison
+fn matches_etags(header: &str, etag: &str, weak_etag: bool, weak_comparison: bool) -> bool {
+    header.split(',')
+        .map(str::trim)
+        .any(|candidate| {
+            if candidate == "*" {
+                return true;
+            }
+
+            if weak_etag && !weak_comparison {
+                return false;
+            }
+
+            let candidate = match candidate.strip_prefix("W/") {
+                Some(_) if !weak_comparison => return false,
+                Some(weak) => weak,
+                None => candidate,
+            };
+
+            candidate.trim_matches('"') == etag
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use super::*;
+
+    const ETAG: &str = "1234";
+    const DATE: &str = "Wed, 01 May 2024 10:00:00 GMT";
+
+    fn last_modified() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 5, 1, 10, 0, 0).unwrap()
+    }
+
+    fn evaluate(conditionals: Conditionals) -> Precondition {
+        evaluate_preconditions(&conditionals, ETAG, false, &last_modified())
+    }
+
+    #[test]
+    fn proceeds_without_conditionals() {
+        assert_eq!(evaluate(Conditionals::default()), Precondition::Proceed);
+    }
+
+    #[test]
+    fn if_none_match() {
+        assert_eq!(evaluate(Conditionals { if_none_match: Some("\"1234\""), ..Default::default() }), Precondition::NotModified);
+        assert_eq!(evaluate(Conditionals { if_none_match: Some("W/\"1234\""), ..Default::default() }), Precondition::NotModified);
+        assert_eq!(evaluate(Conditionals { if_none_match: Some("\"5678\", \"1234\""), ..Default::default() }), Precondition::NotModified);
+        assert_eq!(evaluate(Conditionals { if_none_match: Some("*"), ..Default::default() }), Precondition::NotModified);
+        assert_eq!(evaluate(Conditionals { if_none_match: Some("\"5678\""), ..Default::default() }), Precondition::Proceed);
+    }
+
+    #[test]
+    fn if_match() {
+        assert_eq!(evaluate(Conditionals { if_match: Some("\"1234\""), ..Default::default() }), Precondition::Proceed);
+        assert_eq!(evaluate(Conditionals { if_match: Some("W/\"1234\""), ..Default::default() }), Precondition::Failed);
+        assert_eq!(evaluate(Conditionals { if_match: Some("\"5678\""), ..Default::default() }), Precondition::Failed);
+    }
+
+    #[test]
+    fn weak_etags_never_match_if_match() {
+        let conditionals = Conditionals { if_match: Some("\"1234\""), ..Default::default() };
+        assert_eq!(evaluate_preconditions(&conditionals, ETAG, true, &last_modified()), Precondition::Failed);
+
+        let conditionals = Conditionals { if_none_match: Some("W/\"1234\""), ..Default::default() };
+        assert_eq!(evaluate_preconditions(&conditionals, ETAG, true, &last_modified()), Precondition::NotModified);
+    }
+
+    #[test]
+    fn if_modified_since() {
+        assert_eq!(evaluate(Conditionals { if_modified_since: Some(DATE), ..Default::default() }), Precondition::NotModified);
+        assert_eq!(evaluate(Conditionals { if_modified_since: Some("Wed, 01 May 2024 09:59:59 GMT"), ..Default::default() }), Precondition::Proceed);
+        assert_eq!(evaluate(Conditionals { if_modified_since: Some("invalid"), ..Default::default() }), Precondition::Proceed);
+    }
+
+    #[test]
+    fn if_modified_since_ignores_sub_seconds() {
+        let last_modified = last_modified() + chrono::Duration::milliseconds(500);
+        let conditionals = Conditionals { if_modified_since: Some(DATE), ..Default::default() };
+
+        assert_eq!(evaluate_preconditions(&conditionals, ETAG, false, &last_modified), Precondition::NotModified);
+    }
+
+    #[test]
+    fn if_unmodified_since() {
+        assert_eq!(evaluate(Conditionals { if_unmodified_since: Some(DATE), ..Default::default() }), Precondition::Proceed);
+        assert_eq!(evaluate(Conditionals { if_unmodified_since: Some("Wed, 01 May 2024 09:59:59 GMT"), ..Default::default() }), Precondition::Failed);
+    }
+
+    #[test]
+    fn etags_take_precedence_over_dates() {
+        let conditionals = Conditionals {
+            if_none_match: Some("\"5678\""),
+            if_modified_since: Some(DATE),
+            ..Default::default()
+        };
+        assert_eq!(evaluate(conditionals), Precondition::Proceed);
+
+        let conditionals = Conditionals {
+            if_match: Some("\"1234\""),
+            if_unmodified_since: Some("Wed, 01 May 2024 09:59:59 GMT"),
+            ..Default::default()
+        };
+        assert_eq!(evaluate(conditionals), Precondition::Proceed);
+    }
+
+    #[test]
+    fn failed_if_match_wins_over_not_modified() {
+        let conditionals = Conditionals {
+            if_match: Some("\"5678\""),
+            if_none_match: Some("\"1234\""),
+            ..Default::default()
+        };
+        assert_eq!(evaluate(conditionals), Precondition::Failed);
+    }
+
+    #[test]
+    fn if_range() {
+        let matches = |if_range: Option<&str>, weak_etag: bool| {
+            let conditionals = Conditionals { if_range, ..Default::default() };
+            matches_if_range(&conditionals, ETAG, weak_etag, &last_modified())
+        };
+
+        assert!(matches(None, false));
+        assert!(matches(Some("\"1234\""), false));
+        assert!(matches(Some(DATE), false));
+        assert!(!matches(Some("\"5678\""), false));
+        assert!(!matches(Some("Wed, 01 May 2024 09:59:59 GMT"), false));
+        assert!(!matches(Some("W/\"1234\""), true));
+    }
+}
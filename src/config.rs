@@ -1,30 +1,109 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::ffi::OsStr;
 use std::fs;
+use std::path::{Path, PathBuf};
 use image::ImageFormat;
-use log::LevelFilter;
+use itertools::Itertools;
+use log::{Level, LevelFilter};
 use mediatype::MediaType;
 use mediatype::names::{AVIF, IMAGE, JPEG, WEBP};
-use regex::Regex;
+use regex::{Regex, RegexSet};
 use ron::extensions::Extensions;
 use ron::Options;
 use serde::Deserialize;
 use crate::error::Error;
+use crate::images;
 use crate::images::OptimizationConfig;
+use crate::utils;
 
 #[derive(Deserialize, Clone, Debug)]
 pub struct Config {
     pub extensions: Vec<Extension>,
     pub default_format: Extension,
+    pub keep_format: Option<bool>,
+    pub format_suffixes: Option<bool>,
+    pub sidecars: Option<bool>,
+    pub webp_passthrough: Option<bool>,
+    pub unsupported_formats: Option<UnsupportedFormats>,
+    pub format_mismatch: Option<FormatMismatch>,
+    pub corrupt_fallback: Option<CorruptFallback>,
+    pub pyramid_manifest: Option<String>,
+    pub background: Option<String>,
     pub roots: Vec<String>,
+    pub case_insensitive: Option<bool>,
+    pub symlink_targets: Option<Vec<String>>,
     pub url: String,
+    pub max_url_length: Option<usize>,
+    pub duplicate_slashes: Option<DuplicateSlashes>,
+    pub proto: Option<String>,
+    pub server_header: Option<bool>,
+    pub formats_header: Option<bool>,
+    pub timing_allow_origin: Option<String>,
+    pub json_errors: Option<bool>,
+    pub canonical_base_url: Option<String>,
+    pub preloads: Option<HashMap<String, Vec<String>>>,
+    pub content_etags: Option<bool>,
+    pub weak_etags: Option<bool>,
+    pub etagless_paths: Option<Vec<String>>,
+    pub cache_generation: Option<String>,
     pub cache_directory: String,
+    pub cache_shard_depth: Option<usize>,
+    pub cache_max_size: Option<u64>,
+    pub cache_ttl: Option<u64>,
     pub pre_optimizer_threads: Option<usize>,
+    pub encode_timeout: Option<u64>,
+    pub min_source_age: Option<u64>,
+    pub verify_output: Option<bool>,
+    pub min_savings_ratio: Option<f32>,
+    pub max_open_files: Option<usize>,
+    pub transfer_timeout: Option<u64>,
+    pub verify_transfer_size: Option<bool>,
+    pub memory_chunk_size: Option<usize>,
+    pub stale_while_revalidate: Option<u32>,
+    pub stale_if_error: Option<u32>,
+    pub not_found_ttl: Option<u32>,
+    pub status_headers: Option<HashMap<u16, Vec<(String, String)>>>,
+    pub fingerprinted_paths: Option<Vec<String>>,
+    pub user_agent_denylist: Option<Vec<String>>,
+    pub blocked_paths: Option<Vec<String>>,
+    pub blocking_authority: Option<String>,
     pub sizes: HashMap<String, Size>,
+    pub save_data_qualities: Option<HashMap<Extension, f32>>,
+    pub quality_curves: Option<HashMap<Extension, Vec<(u64, f32)>>>,
+    pub client_hints: Option<bool>,
+    pub critical_client_hints: Option<bool>,
     pub logger: Option<Logger>,
+    pub varnish_log_level: Option<LevelFilter>,
+    pub debug_headers: Option<bool>,
 
     #[serde(skip_deserializing)]
     pub url_regex: Option<Regex>,
 
+    #[serde(skip_deserializing)]
+    pub optional_parts_regex: Option<Regex>,
+
+    #[serde(skip_deserializing)]
+    pub user_agent_denylist_regex: Option<RegexSet>,
+
+    #[serde(skip_deserializing)]
+    pub blocked_paths_regex: Option<RegexSet>,
+
+    #[serde(skip_deserializing)]
+    pub fingerprinted_paths_regex: Option<RegexSet>,
+
+    #[serde(skip_deserializing)]
+    pub etagless_paths_regex: Option<RegexSet>,
+
+    #[serde(skip_deserializing)]
+    pub preloads_regex: Vec<(Regex, Vec<String>)>,
+
+    #[serde(skip_deserializing)]
+    pub save_data_sizes: HashMap<String, Size>,
+
+    #[serde(skip_deserializing)]
+    pub background_rgb: [u8; 3],
+
     #[serde(rename = "qualities")]
     pub quality_serialized: Option<HashMap<Extension, f32>>,
 }
@@ -37,10 +116,15 @@ pub struct Size {
     pub quality: [f32; 3],
     pub pattern: Option<String>,
     pub pre_optimize: Option<bool>,
+    pub upscale: Option<bool>,
+    pub fit: Option<Fit>,
 
     #[serde(skip_deserializing)]
     pub pattern_regex: Option<Regex>,
 
+    #[serde(skip_deserializing)]
+    pub quality_curves: HashMap<Extension, Vec<(u64, f32)>>,
+
     #[serde(rename = "qualities")]
     pub quality_serialized: Option<HashMap<Extension, f32>>,
 }
@@ -51,7 +135,42 @@ pub struct Logger {
     pub level: Option<LevelFilter>,
 }
 
-#[derive(Deserialize, Eq, PartialEq, Hash, Copy, Clone, Debug)]
+#[derive(Deserialize, Eq, PartialEq, Copy, Clone, Debug)]
+pub enum UnsupportedFormats {
+    Passthrough,
+    Reject,
+}
+
+#[derive(Deserialize, Eq, PartialEq, Copy, Clone, Debug)]
+pub enum DuplicateSlashes {
+    Keep,
+    Collapse,
+    Redirect,
+}
+
+#[derive(Deserialize, Eq, PartialEq, Copy, Clone, Debug)]
+pub enum Fit {
+    Inside,
+    Contain,
+    Cover,
+    Fill,
+}
+
+#[derive(Deserialize, Eq, PartialEq, Clone, Debug)]
+pub enum CorruptFallback {
+    Passthrough,
+    Placeholder(String),
+    Reject,
+}
+
+#[derive(Deserialize, Eq, PartialEq, Copy, Clone, Debug)]
+pub enum FormatMismatch {
+    TrustContent,
+    TrustExtension,
+    Reject,
+}
+
+#[derive(Deserialize, Eq, PartialEq, Hash, Ord, PartialOrd, Copy, Clone, Debug)]
 #[repr(u8)]
 pub enum Extension {
     JPEG,
@@ -85,6 +204,12 @@ impl Extension {
         }
     }
 
+    pub fn from_path(path: &str) -> Option<Extension> {
+        Path::new(path).extension()
+            .and_then(OsStr::to_str)
+            .and_then(Extension::from_ext)
+    }
+
     pub fn default_quality(&self) -> f32 {
         match self {
             Extension::JPEG => 90.0, //TODO find value
@@ -106,6 +231,24 @@ impl Extension {
     }
 }
 
+const OPTIONAL_PARTS: &str = r"\[([^\]]*)\]";
+
+//captures the size, path and extension of the requested urls, none if the pattern is invalid
+fn url_regex(url: &str) -> Result<Option<Regex>, Error> {
+    let clean_url = format!(r"^{}$", regex::escape(url))
+        .replace(r"\{size\}", r"(?<size>\w+)")
+        .replace(r"\{path\}", r"(?<path>[^\.]+)")
+        .replace(r"\{ext\}", r"(?<ext>\w+)")
+        .replace(r"\[", "(")
+        .replace(r"\]", ")?");
+
+    if clean_url.chars().filter(|c| *c == '[').count() != clean_url.chars().filter(|c| *c == ']').count() {
+        return Ok(None);
+    }
+
+    Ok(Some(Regex::new(&clean_url)?))
+}
+
 impl Config {
     pub fn parse(path: Option<&str>) -> Result<Config, Error> {
         let path = path.unwrap_or("impress.ron").to_owned();
@@ -115,18 +258,65 @@ impl Config {
                 .with_default_extension(Extensions::IMPLICIT_SOME)
                 .from_str::<Config>(&config)?;
 
-            let clean_url = format!(r"^{}$", regex::escape(&config.url))
-                .replace(r"\{size\}", r"(?<size>\w+)")
-                .replace(r"\{path\}", r"(?<path>[^\.]+)")
-                .replace(r"\{ext\}", r"(?<ext>\w+)")
-                .replace(r"\[", "(")
-                .replace(r"\]", ")?");
-
-            if clean_url.chars().filter(|c| *c == '[').count() != clean_url.chars().filter(|c| *c == ']').count() {
+            let Some(url_regex) = url_regex(&config.url)? else {
                 return Error::err(format!("Invalid URL pattern in config file {}", path));
+            };
+
+            config.url_regex = Some(url_regex);
+            config.optional_parts_regex = Some(Regex::new(OPTIONAL_PARTS)?);
+
+            //a typo in a root would otherwise only show up as 404s on every image
+            for root in &config.roots {
+                if let Err(error) = fs::read_dir(root) {
+                    return Error::err(format!("Invalid root {} in config file {}: {}", root, path, error));
+                }
             }
 
-            config.url_regex = Some(Regex::new(&clean_url)?);
+            let background = config.background.as_deref().unwrap_or("FFFFFF").trim_start_matches('#');
+            match u32::from_str_radix(background, 16) {
+                Ok(rgb) if background.len() == 6 => config.background_rgb = [(rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8],
+                _ => return Error::err(format!("Invalid background color {}, it must be formatted as RRGGBB", background)),
+            }
+
+            if config.min_savings_ratio.is_some_and(|ratio| !(0.0..=1.0).contains(&ratio)) {
+                return Error::err("Invalid min_savings_ratio, it must be between 0 and 1");
+            }
+
+            if config.memory_chunk_size == Some(0) {
+                return Error::err("Invalid memory_chunk_size, it must be greater than 0");
+            }
+
+            //a broken placeholder would otherwise only show up when serving the first corrupt image
+            if let Some(CorruptFallback::Placeholder(placeholder)) = &config.corrupt_fallback {
+                if let Err(error) = images::read(placeholder, true) {
+                    return Error::err(format!("Invalid placeholder {} in config file {}: {}", placeholder, path, error));
+                }
+            }
+
+            //varnish silently turns any other protocol into an unknown one
+            if let Some(proto) = config.proto.as_deref().filter(|proto| !matches!(*proto, "HTTP/1.0" | "HTTP/1.1")) {
+                return Error::err(format!("Invalid proto {}, it must be HTTP/1.0 or HTTP/1.1", proto));
+            }
+
+            if let Some(user_agents) = &config.user_agent_denylist {
+                config.user_agent_denylist_regex = Some(RegexSet::new(user_agents)?);
+            }
+
+            if let Some(paths) = &config.blocked_paths {
+                config.blocked_paths_regex = Some(RegexSet::new(paths)?);
+            }
+
+            if let Some(paths) = &config.fingerprinted_paths {
+                config.fingerprinted_paths_regex = Some(RegexSet::new(paths)?);
+            }
+
+            if let Some(paths) = &config.etagless_paths {
+                config.etagless_paths_regex = Some(RegexSet::new(paths)?);
+            }
+
+            for (pattern, urls) in config.preloads.iter().flatten() {
+                config.preloads_regex.push((Regex::new(pattern)?, urls.clone()));
+            }
 
             for size in &mut config.sizes.values_mut() {
                 for extension in Extension::values() {
@@ -139,6 +329,19 @@ impl Config {
                         *quality
                     } else {
                         extension.default_quality()
+                    };
+
+                    OptimizationConfig::new(size, extension, false).validate()?;
+
+                    //qualities of the size take precedence over the curve
+                    let curve = config.quality_curves.as_ref().and_then(|c| c.get(&extension));
+                    if let (None, Some(curve)) = (size_quality, curve) {
+                        for (_, quality) in curve {
+                            OptimizationConfig::with_quality(extension, *quality, false).validate()?;
+                        }
+
+                        let curve = curve.iter().copied().sorted_by_key(|(pixels, _)| *pixels).collect();
+                        size.quality_curves.insert(extension, curve);
                     }
                 }
 
@@ -151,6 +354,22 @@ impl Config {
 
             config.quality_serialized = None;
 
+            //clients asking to save data get the same sizes with lower qualities, they
+            //can not be requested directly as their name does not match the url pattern
+            if let Some(qualities) = &config.save_data_qualities {
+                for (name, size) in &config.sizes {
+                    let mut save_data_size = size.clone();
+                    save_data_size.pre_optimize = Some(false);
+
+                    for (extension, quality) in qualities {
+                        save_data_size.quality[*extension as usize] = *quality;
+                        save_data_size.quality_curves.remove(extension);
+                        OptimizationConfig::new(&save_data_size, *extension, false).validate()?;
+                    }
+
+                    config.save_data_sizes.insert(Config::save_data_size_name(name), save_data_size);
+                }
+            }
 
             Ok(config)
         } else {
@@ -159,17 +378,213 @@ impl Config {
     }
 }
 
+impl Config {
+    pub fn size(&self, name: &str) -> Option<&Size> {
+        self.sizes.get(name).or_else(|| self.save_data_sizes.get(name))
+    }
+
+    //images are indexed, looked up and cached under their lowercased
+    //path on case insensitive filesystems
+    pub fn image_id<'a>(&self, path: &'a str) -> Cow<'a, str> {
+        if self.case_insensitive.unwrap_or(false) {
+            Cow::Owned(path.to_lowercase())
+        } else {
+            Cow::Borrowed(path)
+        }
+    }
+
+    pub fn save_data_size_name(name: &str) -> String {
+        format!("{}@save-data", name)
+    }
+
+    //formats an image gets optimized to, only its own format when it has to be kept
+    pub fn extensions_for(&self, base_image_path: &str) -> Vec<Extension> {
+        if self.keep_format.unwrap_or(false) {
+            Extension::from_path(base_image_path).into_iter().collect()
+        } else {
+            self.extensions.clone()
+        }
+    }
+
+    //name of an optimized image relative to the cache directory, every
+    //cache entry is named through it and changing it invalidates the cache
+    pub fn cache_key(&self, size: &str, image_id: &str, extension: Extension) -> String {
+        let mut key = PathBuf::from(size);
+
+        //each level of sharding is a directory named after
+        //the next two hexadecimal digits of the hash
+        let hash = format!("{:016x}", utils::stable_hash(image_id));
+        for level in 0..self.cache_shard_depth.unwrap_or(0).min(hash.len() / 2) {
+            key.push(&hash[level * 2..level * 2 + 2]);
+        }
+
+        key.push(image_id);
+        key.set_extension(extension.extensions().first().unwrap());
+
+        key.to_string_lossy().to_string()
+    }
+
+    pub fn cache_path(&self, size: &str, image_id: &str, extension: Extension) -> PathBuf {
+        Path::new(&self.cache_directory).join(self.cache_key(size, image_id, extension))
+    }
+
+    //urls like `/media/default/photo.jpeg.webp` serve the image of the url without
+    //the suffix converted to the format of the suffix, regardless of the accept header
+    pub fn strip_format_suffix<'a>(&self, url: &'a str) -> (&'a str, Option<Extension>) {
+        if !self.format_suffixes.unwrap_or(false) {
+            return (url, None);
+        }
+
+        //the suffix comes after the extension of the image
+        let Some((stripped, suffix)) = url.rsplit_once('.') else {
+            return (url, None);
+        };
+
+        if utils::decompose_filename(stripped).1.is_none() {
+            return (url, None);
+        }
+
+        match Extension::from_ext(suffix) {
+            Some(extension) if self.extensions.contains(&extension) && self.url_regex.as_ref().is_some_and(|regex| regex.is_match(stripped)) => (stripped, Some(extension)),
+            _ => (url, None),
+        }
+    }
+
+    //fingerprinted images get a new path whenever their content changes, their
+    //originals can be cached as long as the optimized images
+    pub fn cache_control(&self, path: &str, is_optimized: bool) -> String {
+        let is_fingerprinted = self.fingerprinted_paths_regex.as_ref().is_some_and(|fingerprinted| fingerprinted.is_match(path));
+        let mut cache_control = String::from(if is_optimized || is_fingerprinted {
+            "public, max-age=31536000, immutable"
+        } else {
+            "no-cache"
+        });
+
+        if let Some(seconds) = self.stale_while_revalidate {
+            cache_control.push_str(&format!(", stale-while-revalidate={}", seconds));
+        }
+
+        if let Some(seconds) = self.stale_if_error {
+            cache_control.push_str(&format!(", stale-if-error={}", seconds));
+        }
+
+        cache_control
+    }
+
+    //messages are logged to the varnish shared memory log when allowed by the configured level
+    pub fn logs_to_varnish(&self, level: Level) -> bool {
+        level <= self.varnish_log_level.unwrap_or(LevelFilter::Error)
+    }
+
+    //webp and avif files next to an image with the same name are alternative formats of it
+    pub fn is_sidecar(&self, path: &str) -> bool {
+        self.sidecars.unwrap_or(false) && matches!(Extension::from_path(path), Some(Extension::WEBP | Extension::AVIF))
+    }
+
+    //whether the path resolves to a file inside one of the roots
+    pub fn is_inside_roots(&self, path: &Path) -> bool {
+        let Ok(target) = fs::canonicalize(path) else {
+            return false;
+        };
+
+        self.roots.iter()
+            .filter_map(|root| fs::canonicalize(root).ok())
+            .any(|root| target.starts_with(root))
+    }
+
+    //symlinks are only followed to the allowed directories when some are configured,
+    //either the image or one of its directories inside the root may be a symlink
+    pub fn is_allowed_symlink(&self, path: &Path) -> bool {
+        let Some(targets) = &self.symlink_targets else {
+            return true;
+        };
+
+        let is_linked = path.ancestors()
+            .take_while(|ancestor| !self.roots.iter().any(|root| Path::new(root) == *ancestor))
+            .any(Path::is_symlink);
+
+        if !is_linked {
+            return true;
+        }
+
+        let Ok(target) = fs::canonicalize(path) else {
+            return false;
+        };
+
+        targets.iter()
+            .filter_map(|directory| fs::canonicalize(directory).ok())
+            .any(|directory| target.starts_with(directory))
+    }
+
+    pub fn build_url(&self, size: &str, path: &str, ext: Option<&str>) -> String {
+        //optional parts of the pattern are only kept when there is an extension to put in them
+        let optional_parts = self.optional_parts_regex.as_ref().expect("Badly initialized config");
+        let url = optional_parts.replace_all(&self.url, if ext.is_some() { "$1" } else { "" });
+
+        //paths are captured decoded, they get encoded back so that they can be put in
+        //srcset candidates and link headers
+        let path = path.split('/').map(urlencoding::encode).join("/");
+
+        url.replace("{size}", size)
+            .replace("{path}", &path)
+            .replace("{ext}", ext.unwrap_or(""))
+    }
+}
+
 impl Default for Config {
     fn default() -> Self {
         Config {
             extensions: vec![Extension::AVIF],
             default_format: Extension::JPEG,
+            keep_format: None,
+            format_suffixes: None,
+            sidecars: None,
+            webp_passthrough: None,
+            unsupported_formats: None,
+            format_mismatch: None,
+            corrupt_fallback: None,
+            pyramid_manifest: None,
+            background: None,
             roots: vec![
                 String::from("/dev/null"),
             ],
+            case_insensitive: None,
+            symlink_targets: None,
             url: String::from("/media"),
+            max_url_length: None,
+            duplicate_slashes: None,
+            proto: None,
+            server_header: None,
+            formats_header: None,
+            timing_allow_origin: None,
+            json_errors: None,
+            canonical_base_url: None,
+            preloads: None,
+            content_etags: None,
+            weak_etags: None,
+            etagless_paths: None,
+            cache_generation: None,
             cache_directory: String::from("/tmp/impress"),
+            cache_shard_depth: None,
+            cache_max_size: None,
+            cache_ttl: None,
             pre_optimizer_threads: None,
+            encode_timeout: None,
+            min_source_age: None,
+            verify_output: None,
+            min_savings_ratio: None,
+            max_open_files: None,
+            transfer_timeout: None,
+            verify_transfer_size: None,
+            memory_chunk_size: None,
+            stale_while_revalidate: None,
+            stale_if_error: None,
+            not_found_ttl: None,
+            status_headers: None,
+            fingerprinted_paths: None,
+            user_agent_denylist: None,
+            blocked_paths: None,
+            blocking_authority: None,
             sizes: HashMap::from([
                 (String::from("default"), Size {
                     width: 500,
@@ -177,12 +592,29 @@ impl Default for Config {
                     quality: [0.0; 3],
                     pattern: None,
                     pre_optimize: None,
+                    upscale: None,
+                    fit: None,
                     pattern_regex: None,
+                    quality_curves: HashMap::new(),
                     quality_serialized: None,
                 }),
             ]),
+            save_data_qualities: None,
+            quality_curves: None,
+            client_hints: None,
+            critical_client_hints: None,
             logger: None,
+            varnish_log_level: None,
+            debug_headers: None,
             url_regex: None,
+            optional_parts_regex: Some(Regex::new(OPTIONAL_PARTS).unwrap()),
+            user_agent_denylist_regex: None,
+            blocked_paths_regex: None,
+            fingerprinted_paths_regex: None,
+            etagless_paths_regex: None,
+            preloads_regex: Vec::new(),
+            save_data_sizes: HashMap::new(),
+            background_rgb: [255, 255, 255],
             quality_serialized: None,
         }
     }
@@ -196,20 +628,47 @@ impl Size {
             true
         }
     }
+
+    //interpolates the quality between the two closest points of the
+    //curve, images outside of the curve get the quality of its ends
+    pub fn quality_for(&self, format: Extension, pixels: u64) -> f32 {
+        let Some(curve) = self.quality_curves.get(&format).filter(|curve| !curve.is_empty()) else {
+            return self.quality[format as usize];
+        };
+
+        let next = curve.iter().position(|(curve_pixels, _)| *curve_pixels >= pixels);
+        match next {
+            Some(0) => curve[0].1,
+            Some(i) => {
+                let (start_pixels, start_quality) = curve[i - 1];
+                let (end_pixels, end_quality) = curve[i];
+                let progress = (pixels - start_pixels) as f32 / (end_pixels - start_pixels) as f32;
+
+                start_quality + (end_quality - start_quality) * progress
+            }
+            None => curve[curve.len() - 1].1,
+        }
+    }
 }
 
 impl OptimizationConfig {
     pub fn new(size: &Size, format: Extension, prefer_quality: bool) -> OptimizationConfig {
-        let quality = size.quality[format as usize];
+        OptimizationConfig::with_quality(format, size.quality[format as usize], prefer_quality)
+    }
 
+    pub fn with_quality(format: Extension, quality: f32, prefer_quality: bool) -> OptimizationConfig {
         match format {
             Extension::WEBP => OptimizationConfig::Webp {
                 quality,
                 prefer_quality,
+                method: 3,
+                alpha_quality: 50,
+                lossless: false,
             },
             Extension::AVIF => OptimizationConfig::Avif {
                 quality,
-                prefer_quality,
+                alpha_quality: 50,
+                speed: if prefer_quality { 0 } else { 6 },
             },
             Extension::JPEG => OptimizationConfig::Jpeg {
                 quality,
@@ -217,4 +676,281 @@ impl OptimizationConfig {
             },
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use image::DynamicImage;
+    use super::*;
+
+    fn size_with_curve(curve: Vec<(u64, f32)>) -> Size {
+        let mut size = Config::default().sizes.remove("default").unwrap();
+        size.quality = [90.0, 70.0, 40.0];
+        size.quality_curves.insert(Extension::WEBP, curve);
+
+        size
+    }
+
+    #[test]
+    fn quality_without_curve() {
+        let size = size_with_curve(Vec::new());
+
+        assert_eq!(size.quality_for(Extension::WEBP, 1000), 70.0);
+        assert_eq!(size.quality_for(Extension::AVIF, 1000), 40.0);
+    }
+
+    #[test]
+    fn quality_is_interpolated_between_the_closest_points() {
+        let size = size_with_curve(vec![(100, 90.0), (200, 70.0), (400, 50.0)]);
+
+        assert_eq!(size.quality_for(Extension::WEBP, 100), 90.0);
+        assert_eq!(size.quality_for(Extension::WEBP, 150), 80.0);
+        assert_eq!(size.quality_for(Extension::WEBP, 200), 70.0);
+        assert_eq!(size.quality_for(Extension::WEBP, 300), 60.0);
+    }
+
+    #[test]
+    fn quality_outside_of_the_curve_is_its_closest_end() {
+        let size = size_with_curve(vec![(100, 90.0), (200, 70.0)]);
+
+        assert_eq!(size.quality_for(Extension::WEBP, 10), 90.0);
+        assert_eq!(size.quality_for(Extension::WEBP, 1000), 70.0);
+    }
+
+    #[test]
+    fn optional_parts_of_the_url_are_only_kept_with_an_extension() {
+        let config = Config {
+            url: String::from("/{size}/{path}[.{ext}]"),
+            ..Config::default()
+        };
+
+        assert_eq!(config.build_url("small", "photo", Some("webp")), "/small/photo.webp");
+        assert_eq!(config.build_url("small", "photo", None), "/small/photo");
+    }
+
+    #[test]
+    fn symlinks_are_only_followed_to_the_allowed_directories() {
+        let directory = std::env::temp_dir().join("impress_symlinks");
+        let _ = fs::remove_dir_all(&directory);
+        for name in ["root", "allowed", "outside"] {
+            fs::create_dir_all(directory.join(name)).unwrap();
+        }
+
+        fs::write(directory.join("root/photo.jpg"), "").unwrap();
+        fs::write(directory.join("allowed/photo.jpg"), "").unwrap();
+        fs::write(directory.join("outside/photo.jpg"), "").unwrap();
+        std::os::unix::fs::symlink(directory.join("allowed/photo.jpg"), directory.join("root/allowed.jpg")).unwrap();
+        std::os::unix::fs::symlink(directory.join("outside/photo.jpg"), directory.join("root/outside.jpg")).unwrap();
+        std::os::unix::fs::symlink(directory.join("allowed"), directory.join("root/linked")).unwrap();
+
+        let root = directory.join("root");
+        let mut config = Config {
+            roots: vec![root.to_string_lossy().to_string()],
+            ..Config::default()
+        };
+
+        for name in ["photo.jpg", "allowed.jpg", "outside.jpg", "linked/photo.jpg"] {
+            assert!(config.is_allowed_symlink(&root.join(name)), "{}", name);
+        }
+
+        config.symlink_targets = Some(vec![directory.join("allowed").to_string_lossy().to_string()]);
+        assert!(config.is_allowed_symlink(&root.join("photo.jpg")));
+        assert!(config.is_allowed_symlink(&root.join("allowed.jpg")));
+        assert!(config.is_allowed_symlink(&root.join("linked/photo.jpg")));
+        assert!(!config.is_allowed_symlink(&root.join("outside.jpg")));
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn built_urls_are_the_requested_urls() {
+        let config = Config {
+            url: String::from("/{size}/{path}[.{ext}]"),
+            ..Config::default()
+        };
+        let pattern = url_regex(&config.url).unwrap().unwrap();
+
+        for url in ["/small/photo.webp", "/small/albums/my%20photo.webp", "/small/a%3Bb%2Cc%3E.webp", "/small/photo"] {
+            let decoded = urlencoding::decode(url).unwrap();
+            let captures = pattern.captures(&decoded).unwrap();
+            let ext = captures.name("ext").map(|ext| ext.as_str());
+
+            assert_eq!(config.build_url(&captures["size"], &captures["path"], ext), url);
+        }
+    }
+
+    #[test]
+    fn cache_keys_are_sharded_by_the_hash_of_the_image() {
+        let key = |depth| Config { cache_shard_depth: depth, ..Config::default() }.cache_key("small", "albums/photo", Extension::WEBP);
+
+        assert_eq!(key(None), "small/albums/photo.webp");
+        assert_eq!(key(Some(0)), "small/albums/photo.webp");
+        assert_eq!(key(Some(1)), "small/2a/albums/photo.webp");
+        assert_eq!(key(Some(2)), "small/2a/1d/albums/photo.webp");
+    }
+
+    //parses a config file made of the given options and of the required ones they do not set
+    fn parse(name: &str, options: &str) -> Result<Config, Error> {
+        let directory = std::env::temp_dir().join(format!("impress_{}", name));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+
+        let required = [
+            ("extensions", String::from("[WEBP]")),
+            ("default_format", String::from("JPEG")),
+            ("roots", format!("[\"{}\"]", directory.display())),
+            ("url", String::from("\"/{size}/{path}[.{ext}]\"")),
+            ("cache_directory", format!("\"{}\"", directory.display())),
+            ("sizes", String::from("{\"small\": (width: 100, height: 100)}")),
+        ];
+
+        let required = required.iter()
+            .filter(|(option, _)| !options.contains(&format!("{}:", option)))
+            .map(|(option, value)| format!("{}: {},", option, value))
+            .join("\n");
+
+        let path = directory.join("impress.ron");
+        fs::write(&path, format!("({}\n{})", required, options)).unwrap();
+
+        let config = Config::parse(Some(path.to_str().unwrap()));
+        fs::remove_dir_all(&directory).unwrap();
+
+        config
+    }
+
+    #[test]
+    fn options_are_validated_at_their_boundaries() {
+        assert!(parse("parse_quality_min", "qualities: {WEBP: 0}").is_ok());
+        assert!(parse("parse_quality_max", "qualities: {WEBP: 100}").is_ok());
+        assert!(parse("parse_quality_over", "qualities: {WEBP: 101}").is_err());
+        assert!(parse("parse_size_quality_over", r#"sizes: {"small": (width: 100, height: 100, qualities: {AVIF: 101})}"#).is_err());
+        assert!(parse("parse_curve_quality_over", "quality_curves: {JPEG: [(1000, 101)]}").is_err());
+
+        assert!(parse("parse_ratio_min", "min_savings_ratio: 0").is_ok());
+        assert!(parse("parse_ratio_max", "min_savings_ratio: 1").is_ok());
+        assert!(parse("parse_ratio_under", "min_savings_ratio: -0.1").is_err());
+        assert!(parse("parse_ratio_over", "min_savings_ratio: 1.1").is_err());
+
+        assert!(parse("parse_chunk_size_min", "memory_chunk_size: 1").is_ok());
+        assert!(parse("parse_chunk_size_zero", "memory_chunk_size: 0").is_err());
+    }
+
+    #[test]
+    fn cache_control_depends_on_the_image() {
+        let config = Config {
+            fingerprinted_paths_regex: Some(RegexSet::new([r"\.[0-9a-f]{8}$"]).unwrap()),
+            ..Config::default()
+        };
+        let stale = Config {
+            stale_while_revalidate: Some(60),
+            stale_if_error: Some(3600),
+            ..Config::default()
+        };
+
+        let cases = [
+            (&config, "photo", true, "public, max-age=31536000, immutable"),
+            (&config, "photo", false, "no-cache"),
+            (&config, "photo.0123abcd", false, "public, max-age=31536000, immutable"),
+            (&stale, "photo", true, "public, max-age=31536000, immutable, stale-while-revalidate=60, stale-if-error=3600"),
+            (&stale, "photo", false, "no-cache, stale-while-revalidate=60, stale-if-error=3600"),
+        ];
+
+        for (config, path, is_optimized, expected) in cases {
+            assert_eq!(config.cache_control(path, is_optimized), expected, "{} {}", path, is_optimized);
+        }
+    }
+
+    #[test]
+    fn only_messages_as_severe_as_the_level_are_logged_to_varnish() {
+        let cases = [
+            (None, Level::Error, true),
+            (None, Level::Warn, false),
+            (Some(LevelFilter::Off), Level::Error, false),
+            (Some(LevelFilter::Warn), Level::Error, true),
+            (Some(LevelFilter::Warn), Level::Warn, true),
+            (Some(LevelFilter::Warn), Level::Info, false),
+            (Some(LevelFilter::Trace), Level::Trace, true),
+        ];
+
+        for (varnish_log_level, level, expected) in cases {
+            let config = Config { varnish_log_level, ..Config::default() };
+            assert_eq!(config.logs_to_varnish(level), expected, "{:?} {:?}", varnish_log_level, level);
+        }
+    }
+
+    #[test]
+    fn only_http_1_protocols_are_allowed() {
+        assert!(parse("parse_proto_1_0", r#"proto: "HTTP/1.0""#).is_ok());
+        assert!(parse("parse_proto_1_1", r#"proto: "HTTP/1.1""#).is_ok());
+        assert!(parse("parse_proto_2", r#"proto: "HTTP/2""#).is_err());
+        assert!(parse("parse_proto_lowercase", r#"proto: "http/1.1""#).is_err());
+    }
+
+    #[test]
+    fn placeholders_are_decoded_when_loading_the_config() {
+        let placeholder = std::env::temp_dir().join("impress_placeholder.png");
+        let options = format!(r#"corrupt_fallback: Placeholder("{}")"#, placeholder.display());
+
+        DynamicImage::new_rgb8(4, 4).save(&placeholder).unwrap();
+        assert!(parse("parse_placeholder", &options).is_ok());
+
+        fs::write(&placeholder, "not an image").unwrap();
+        assert!(parse("parse_corrupt_placeholder", &options).is_err());
+
+        fs::remove_file(&placeholder).unwrap();
+        assert!(parse("parse_missing_placeholder", &options).is_err());
+    }
+
+    #[test]
+    fn unreadable_roots_fail_loading_the_config() {
+        let error = parse("parse_missing_root", r#"roots: ["/nonexistent/impress"]"#).unwrap_err();
+        assert!(error.to_string().starts_with("Invalid root /nonexistent/impress"), "{}", error);
+    }
+
+    #[test]
+    fn format_suffixes_force_the_format_of_the_image() {
+        let mut config = Config {
+            extensions: vec![Extension::WEBP, Extension::AVIF],
+            url: String::from("/media/{size}/{path}.{ext}"),
+            format_suffixes: Some(true),
+            ..Config::default()
+        };
+        config.url_regex = url_regex(&config.url).unwrap();
+
+        assert_eq!(config.strip_format_suffix("/media/small/photo.jpeg.webp"), ("/media/small/photo.jpeg", Some(Extension::WEBP)));
+        assert_eq!(config.strip_format_suffix("/media/small/photo.jpeg.avif"), ("/media/small/photo.jpeg", Some(Extension::AVIF)));
+        assert_eq!(config.strip_format_suffix("/media/small/photo.jpeg"), ("/media/small/photo.jpeg", None));
+        assert_eq!(config.strip_format_suffix("/media/small/photo.jpeg.gif"), ("/media/small/photo.jpeg.gif", None));
+
+        config.extensions = vec![Extension::AVIF];
+        assert_eq!(config.strip_format_suffix("/media/small/photo.jpeg.webp"), ("/media/small/photo.jpeg.webp", None));
+
+        config.format_suffixes = None;
+        assert_eq!(config.strip_format_suffix("/media/small/photo.jpeg.avif"), ("/media/small/photo.jpeg.avif", None));
+    }
+
+    #[test]
+    fn format_suffixes_take_precedence_over_literal_files() {
+        let directory = std::env::temp_dir().join("impress_literal_suffix");
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(directory.join("photo.jpeg"), "").unwrap();
+        fs::write(directory.join("photo.jpeg.webp"), "").unwrap();
+
+        let mut config = Config {
+            extensions: vec![Extension::WEBP],
+            roots: vec![directory.to_string_lossy().to_string()],
+            url: String::from("/media/{size}/{path}.{ext}"),
+            format_suffixes: Some(true),
+            ..Config::default()
+        };
+        let pattern = url_regex(&config.url).unwrap().unwrap();
+        config.url_regex = Some(pattern.clone());
+
+        //paths never contain dots, the literal file can not be requested
+        assert!(!pattern.is_match("/media/small/photo.jpeg.webp"));
+        assert_eq!(config.strip_format_suffix("/media/small/photo.jpeg.webp"), ("/media/small/photo.jpeg", Some(Extension::WEBP)));
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
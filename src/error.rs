@@ -2,6 +2,10 @@ use std::fmt;
 
 #[derive(Debug)]
 pub enum Error {
+    Timeout,
+    BadRequest(String),
+    UnsupportedMediaType,
+    Corrupt(String),
     Other(String),
 }
 
@@ -18,6 +22,10 @@ impl Error {
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
         match self {
+            Error::Timeout => write!(f, "Operation timed out"),
+            Error::BadRequest(s) => write!(f, "{}", s),
+            Error::UnsupportedMediaType => write!(f, "Unsupported image format"),
+            Error::Corrupt(s) => write!(f, "Corrupt image: {}", s),
             Error::Other(s) => write!(f, "{}", s),
         }
     }
@@ -20,7 +20,7 @@ impl Into<Avif> for AvifData<'static> {
     }
 }
 
-pub fn to_avif(image: &DynamicImage, quality: f32, prefer_quality: bool) -> Avif {
+pub fn to_avif(image: &DynamicImage, quality: f32, alpha_quality: u8, speed: u8) -> Avif {
     let image = {
         let width = image.width();
         let height = image.height();
@@ -35,9 +35,9 @@ pub fn to_avif(image: &DynamicImage, quality: f32, prefer_quality: bool) -> Avif
 
     Encoder::new()
         .set_quality(quality as u8) //TODO: allow different quality for avif and webp, 40
-        .set_alpha_quality(50)
+        .set_alpha_quality(alpha_quality)
         .set_max_threads(1)
-        .set_speed(if prefer_quality { 0 } else { 6 })
+        .set_speed(speed)
         .encode(&image)
         .expect("Failed to encode to AVIF")
         .into()
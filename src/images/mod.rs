@@ -4,15 +4,30 @@ mod jpeg;
 
 use std::fs;
 use std::fs::File;
-use std::io::Write;
+use std::io::{BufReader, Read, Seek, Write};
 use std::path::Path;
-use std::time::SystemTime;
-use image::DynamicImage;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+use image::{imageops, AnimationDecoder, DynamicImage, ImageDecoder, ImageError, ImageFormat, ImageResult, Rgb, Rgba, RgbImage, RgbaImage};
+use image::codecs::gif::GifDecoder;
+use image::codecs::jpeg::JpegDecoder;
+use image::codecs::png::PngDecoder;
+use image::codecs::webp::WebPDecoder;
 use image::imageops::FilterType;
+use crate::config::{Extension, Fit, FormatMismatch};
 use crate::error::Error;
 
-pub fn read<T>(path: T) -> Result<DynamicImage, Error> where T: AsRef<Path> {
-    let image = image::open(path)?;
+//the decoder is chosen from the content of the file when it is trusted, from its extension otherwise
+pub fn read<T>(path: T, trust_content: bool) -> Result<DynamicImage, Error> where T: AsRef<Path> {
+    let image = if trust_content {
+        image::io::Reader::open(path)?.with_guessed_format()?.decode()
+    } else {
+        image::open(path)
+    };
+
+    let image = image.map_err(decoding_error)?;
+
     if matches!(&image, DynamicImage::ImageRgb8(_)) || matches!(&image, DynamicImage::ImageRgba8(_)) {
         Ok(image)
     } else {
@@ -20,20 +35,185 @@ pub fn read<T>(path: T) -> Result<DynamicImage, Error> where T: AsRef<Path> {
     }
 }
 
-pub fn resize(image: &DynamicImage, width: u32, height: u32) -> DynamicImage {
-    image.resize(width, height, FilterType::Lanczos3)
+//dimensions of an image and whether it has an alpha channel, read without decoding its pixels
+pub fn describe<T>(path: T) -> Result<(u32, u32, bool), Error> where T: AsRef<Path> {
+    let reader = image::io::Reader::open(&path)?.with_guessed_format()?;
+    let format = reader.format();
+    let reader = reader.into_inner();
+
+    match format {
+        Some(ImageFormat::Jpeg) => describe_decoder(JpegDecoder::new(reader)),
+        Some(ImageFormat::Png) => describe_decoder(PngDecoder::new(reader)),
+        Some(ImageFormat::Gif) => describe_decoder(GifDecoder::new(reader)),
+        Some(ImageFormat::WebP) => describe_decoder(WebPDecoder::new(reader)),
+        //formats that can not be decoded only have their dimensions read
+        _ => {
+            let (width, height) = image::image_dimensions(&path).map_err(decoding_error)?;
+            Ok((width, height, false))
+        }
+    }
+}
+
+fn describe_decoder<'a, D>(decoder: ImageResult<D>) -> Result<(u32, u32, bool), Error> where D: ImageDecoder<'a> {
+    let decoder = decoder.map_err(decoding_error)?;
+    let (width, height) = decoder.dimensions();
+
+    Ok((width, height, decoder.color_type().has_alpha()))
+}
+
+//files that can be read but not decoded are told apart from io errors
+fn decoding_error(error: ImageError) -> Error {
+    match error {
+        ImageError::Decoding(error) => Error::Corrupt(error.to_string()),
+        error => error.into(),
+    }
+}
+
+//format of the file according to its first bytes, the file is rewound afterward
+pub fn sniff_format(file: &mut File) -> Result<Option<ImageFormat>, Error> {
+    let mut header = Vec::with_capacity(32);
+    file.take(32).read_to_end(&mut header)?;
+    file.rewind()?;
+
+    Ok(image::guess_format(&header).ok())
+}
+
+//format a file is served and decoded as when its content does not match its extension
+pub fn resolve_format(extension: ImageFormat, sniffed: Option<ImageFormat>, policy: FormatMismatch) -> Result<ImageFormat, Error> {
+    match sniffed {
+        Some(sniffed) if sniffed != extension => match policy {
+            FormatMismatch::TrustContent => Ok(sniffed),
+            FormatMismatch::TrustExtension => Ok(extension),
+            FormatMismatch::Reject => Err(Error::UnsupportedMediaType),
+        },
+        _ => Ok(extension),
+    }
+}
+
+pub fn can_decode<T>(path: T) -> bool where T: AsRef<Path> {
+    ImageFormat::from_path(path).is_ok_and(|format| format.can_read() && format.reading_enabled())
+}
+
+//formats whose decoder is compiled in, identified by their main extension
+pub fn supported_inputs() -> Vec<&'static str> {
+    ImageFormat::all()
+        .filter(|format| format.can_read() && format.reading_enabled())
+        .filter_map(|format| format.extensions_str().first().copied())
+        .collect()
+}
+
+pub fn supported_outputs() -> Vec<&'static str> {
+    Extension::values().iter()
+        .filter_map(|extension| extension.extensions().first().copied())
+        .collect()
+}
+
+//composites transparent images over the background for formats without alpha channel
+pub fn flatten(image: DynamicImage, background: [u8; 3]) -> DynamicImage {
+    let DynamicImage::ImageRgba8(image) = image else {
+        return image;
+    };
+
+    let flattened = RgbImage::from_fn(image.width(), image.height(), |x, y| {
+        let [r, g, b, a] = image.get_pixel(x, y).0;
+        let blend = |color: u8, background: u8| ((color as u32 * a as u32 + background as u32 * (255 - a as u32)) / 255) as u8;
+
+        Rgb([blend(r, background[0]), blend(g, background[1]), blend(b, background[2])])
+    });
+
+    DynamicImage::ImageRgb8(flattened)
+}
+
+//only the first frame of animations would be kept when optimizing them
+pub fn is_animated<T>(path: T) -> Result<bool, Error> where T: AsRef<Path> {
+    let reader = BufReader::new(File::open(&path)?);
+
+    match ImageFormat::from_path(&path)? {
+        ImageFormat::Gif => Ok(GifDecoder::new(reader).map_err(decoding_error)?.into_frames().take(2).count() > 1),
+        ImageFormat::WebP => Ok(WebPDecoder::new(reader).map_err(decoding_error)?.has_animation()),
+        _ => Ok(false),
+    }
+}
+
+pub fn resize(image: &DynamicImage, width: u32, height: u32, upscale: bool, fit: Fit, background: [u8; 3]) -> DynamicImage {
+    //images already fitting in the requested box are left untouched unless upscaling was
+    //explicitly allowed, it would only waste bytes
+    if !upscale && image.width() <= width && image.height() <= height {
+        return image.clone();
+    }
+
+    match fit {
+        Fit::Inside => image.resize(width, height, FilterType::Lanczos3),
+        Fit::Cover => {
+            //images smaller than the box are cropped to its aspect ratio without being enlarged
+            let scale = (image.width() as f64 / width as f64).min(image.height() as f64 / height as f64);
+            let (width, height) = if !upscale && scale < 1.0 {
+                (((width as f64 * scale).round() as u32).max(1), ((height as f64 * scale).round() as u32).max(1))
+            } else {
+                (width, height)
+            };
+
+            image.resize_to_fill(width, height, FilterType::Lanczos3)
+        }
+        Fit::Fill if !upscale => image.resize_exact(width.min(image.width()), height.min(image.height()), FilterType::Lanczos3),
+        Fit::Fill => image.resize_exact(width, height, FilterType::Lanczos3),
+        Fit::Contain => {
+            //the image is centered in the box, the remaining space is filled with the background
+            let resized = image.resize(width, height, FilterType::Lanczos3);
+            let [r, g, b] = background;
+            let mut canvas = RgbaImage::from_pixel(width, height, Rgba([r, g, b, 255]));
+            let x = (width - resized.width()) / 2;
+            let y = (height - resized.height()) / 2;
+            imageops::overlay(&mut canvas, &resized.to_rgba8(), x as i64, y as i64);
+
+            DynamicImage::ImageRgba8(canvas)
+        }
+    }
 }
 
 pub fn optimize(image: &DynamicImage, config: OptimizationConfig) -> Result<Box<dyn OptimizedImage>, Error> {
     let optimized: Box<dyn OptimizedImage> = match config {
-        OptimizationConfig::Webp { quality, prefer_quality } => Box::new(webp::to_webp(&image, quality, prefer_quality)),
-        OptimizationConfig::Avif { quality, prefer_quality } => Box::new(avif::to_avif(&image, quality, prefer_quality)),
+        OptimizationConfig::Webp { quality, prefer_quality, method, alpha_quality, lossless } => Box::new(webp::to_webp(&image, quality, prefer_quality, method, alpha_quality, lossless)),
+        OptimizationConfig::Avif { quality, alpha_quality, speed } => Box::new(avif::to_avif(&image, quality, alpha_quality, speed)),
         OptimizationConfig::Jpeg { quality, prefer_quality } => Box::new(jpeg::to_jpeg(&image, quality, prefer_quality)?),
     };
 
     Ok(optimized)
 }
 
+//the encoded data is copied as optimized images can not be sent across threads
+pub fn optimize_with_timeout(image: DynamicImage, config: OptimizationConfig, timeout: Option<Duration>) -> Result<Vec<u8>, Error> {
+    let Some(timeout) = timeout else {
+        return Ok(optimize(&image, config)?.data().to_vec());
+    };
+
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = tx.send(optimize(&image, config).map(|optimized| optimized.data().to_vec()));
+    });
+
+    match rx.recv_timeout(timeout) {
+        Ok(result) => result,
+        Err(mpsc::RecvTimeoutError::Timeout) => Err(Error::Timeout),
+        Err(mpsc::RecvTimeoutError::Disconnected) => Error::err("Encoder stopped unexpectedly"),
+    }
+}
+
+//decodes encoded images to make sure they have the expected dimensions, images
+//in formats that can not be decoded, like avif without its decoder, are trusted
+pub fn verify(data: &[u8], format: ImageFormat, width: u32, height: u32) -> Result<(), Error> {
+    if !format.reading_enabled() {
+        return Ok(());
+    }
+
+    let decoded = image::load_from_memory_with_format(data, format)?;
+    if decoded.width() != width || decoded.height() != height {
+        return Error::err(format!("Encoded image is {}x{} instead of {}x{}", decoded.width(), decoded.height(), width, height));
+    }
+
+    Ok(())
+}
+
 pub fn write<T>(path: T, data: &[u8], last_modified: Option<SystemTime>) -> Result<(), Error> where T: AsRef<Path> {
     fs::create_dir_all(path.as_ref().parent().unwrap()).unwrap();
 
@@ -48,11 +228,221 @@ pub fn write<T>(path: T, data: &[u8], last_modified: Option<SystemTime>) -> Resu
 }
 
 pub enum OptimizationConfig {
-    Webp { quality: f32, prefer_quality: bool },
-    Avif { quality: f32, prefer_quality: bool },
+    Webp { quality: f32, prefer_quality: bool, method: u8, alpha_quality: u8, lossless: bool },
+    Avif { quality: f32, alpha_quality: u8, speed: u8 },
     Jpeg { quality: f32, prefer_quality: bool },
 }
 
+impl OptimizationConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        let (format, quality) = match self {
+            OptimizationConfig::Webp { quality, .. } => ("WEBP", *quality),
+            OptimizationConfig::Avif { quality, .. } => ("AVIF", *quality),
+            OptimizationConfig::Jpeg { quality, .. } => ("JPEG", *quality),
+        };
+
+        if !(0.0..=100.0).contains(&quality) {
+            return Error::err(format!("Invalid {} quality {}, it must be between 0 and 100", format, quality));
+        }
+
+        match *self {
+            OptimizationConfig::Webp { method, alpha_quality, .. } => {
+                validate_range(format, "method", method, 6)?;
+                validate_range(format, "alpha quality", alpha_quality, 100)
+            }
+            OptimizationConfig::Avif { alpha_quality, speed, .. } => {
+                validate_range(format, "alpha quality", alpha_quality, 100)?;
+                validate_range(format, "speed", speed, 10)
+            }
+            OptimizationConfig::Jpeg { .. } => Ok(()),
+        }
+    }
+}
+
+fn validate_range(format: &str, field: &str, value: u8, max: u8) -> Result<(), Error> {
+    if value > max {
+        return Error::err(format!("Invalid {} {} {}, it must be between 0 and {}", format, field, value, max));
+    }
+
+    Ok(())
+}
+
 pub trait OptimizedImage {
     fn data(&self) -> &[u8];
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use super::*;
+
+    //png content behind a jpeg extension
+    fn mismatched_image(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("impress_{}.jpg", name));
+        DynamicImage::new_rgb8(4, 4).save_with_format(&path, ImageFormat::Png).unwrap();
+
+        path
+    }
+
+    #[test]
+    fn mismatched_formats_are_resolved_by_the_policy() {
+        let (jpeg, png) = (ImageFormat::Jpeg, ImageFormat::Png);
+
+        assert_eq!(resolve_format(jpeg, Some(png), FormatMismatch::TrustContent).unwrap(), png);
+        assert_eq!(resolve_format(jpeg, Some(png), FormatMismatch::TrustExtension).unwrap(), jpeg);
+        assert!(matches!(resolve_format(jpeg, Some(png), FormatMismatch::Reject), Err(Error::UnsupportedMediaType)));
+    }
+
+    #[test]
+    fn matching_formats_are_accepted_by_every_policy() {
+        for policy in [FormatMismatch::TrustContent, FormatMismatch::TrustExtension, FormatMismatch::Reject] {
+            assert_eq!(resolve_format(ImageFormat::Jpeg, Some(ImageFormat::Jpeg), policy).unwrap(), ImageFormat::Jpeg);
+            assert_eq!(resolve_format(ImageFormat::Jpeg, None, policy).unwrap(), ImageFormat::Jpeg);
+        }
+    }
+
+    #[test]
+    fn mismatched_files_are_decoded_according_to_the_trusted_format() {
+        let path = mismatched_image("mismatched_decoding");
+
+        let sniffed = sniff_format(&mut File::open(&path).unwrap()).unwrap();
+        let content = read(&path, true);
+        let extension = read(&path, false);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(sniffed, Some(ImageFormat::Png));
+        assert_eq!(content.unwrap().width(), 4);
+        assert!(matches!(extension, Err(Error::Corrupt(_))));
+    }
+
+    #[test]
+    fn optimization_configs_are_validated_at_their_boundaries() {
+        let webp = |quality, method, alpha_quality| OptimizationConfig::Webp { quality, prefer_quality: false, method, alpha_quality, lossless: false }.validate().is_ok();
+        let avif = |quality, alpha_quality, speed| OptimizationConfig::Avif { quality, alpha_quality, speed }.validate().is_ok();
+        let jpeg = |quality| OptimizationConfig::Jpeg { quality, prefer_quality: false }.validate().is_ok();
+
+        assert!(webp(0.0, 0, 0) && webp(100.0, 6, 100));
+        assert!(!webp(-1.0, 3, 50) && !webp(101.0, 3, 50));
+        assert!(!webp(80.0, 7, 50) && !webp(80.0, 3, 101));
+
+        assert!(avif(0.0, 0, 0) && avif(100.0, 100, 10));
+        assert!(!avif(-1.0, 50, 6) && !avif(101.0, 50, 6));
+        assert!(!avif(50.0, 101, 6) && !avif(50.0, 50, 11));
+
+        assert!(jpeg(0.0) && jpeg(100.0));
+        assert!(!jpeg(-1.0) && !jpeg(101.0));
+    }
+
+    #[test]
+    fn lossless_webp_keeps_every_pixel() {
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_fn(16, 16, |x, y| image::Rgb([x as u8 * 16, y as u8 * 16, 128])));
+        let config = OptimizationConfig::Webp { quality: 75.0, prefer_quality: false, method: 3, alpha_quality: 50, lossless: true };
+
+        let optimized = optimize(&image, config).unwrap();
+        let decoded = image::load_from_memory_with_format(optimized.data(), ImageFormat::WebP).unwrap();
+
+        assert_eq!(decoded.to_rgb8(), image.to_rgb8());
+    }
+
+    #[test]
+    fn webp_is_encoded_at_every_method() {
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_fn(32, 16, |x, y| image::Rgb([x as u8 * 8, y as u8 * 16, 128])));
+
+        for method in 0..=6 {
+            let config = OptimizationConfig::Webp { quality: 75.0, prefer_quality: false, method, alpha_quality: 50, lossless: false };
+            let optimized = optimize(&image, config).unwrap();
+
+            assert!(verify(optimized.data(), ImageFormat::WebP, 32, 16).is_ok(), "method {}", method);
+        }
+    }
+
+    #[test]
+    fn transparent_images_are_flattened_over_the_background() {
+        let path = std::env::temp_dir().join("impress_transparent.png");
+        let transparent = image::RgbaImage::from_fn(16, 16, |x, y| {
+            let inside = (4..12).contains(&x) && (4..12).contains(&y);
+            image::Rgba(if inside { [0, 0, 255, 255] } else { [0, 255, 0, 0] })
+        });
+        transparent.save(&path).unwrap();
+
+        let image = read(&path, false).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let flattened = flatten(image, [255, 0, 0]);
+        assert_eq!(flattened.to_rgb8().get_pixel(0, 0).0, [255, 0, 0]);
+        assert_eq!(flattened.to_rgb8().get_pixel(8, 8).0, [0, 0, 255]);
+
+        let config = OptimizationConfig::Jpeg { quality: 90.0, prefer_quality: false };
+        let jpeg = optimize(&flattened, config).unwrap();
+        let decoded = image::load_from_memory_with_format(jpeg.data(), ImageFormat::Jpeg).unwrap().to_rgb8();
+
+        for (x, y) in [(0, 0), (15, 0), (0, 15), (15, 15)] {
+            let [r, g, b] = decoded.get_pixel(x, y).0;
+            assert!(r > 240 && g < 15 && b < 15, "corner {}x{} is {:?}", x, y, [r, g, b]);
+        }
+    }
+
+    #[test]
+    fn translucent_pixels_are_blended_with_the_background() {
+        let image = DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(1, 1, image::Rgba([0, 0, 0, 51])));
+
+        assert_eq!(flatten(image, [255, 255, 255]).to_rgb8().get_pixel(0, 0).0, [204, 204, 204]);
+    }
+
+    fn resized(width: u32, height: u32, upscale: bool, fit: Fit) -> (u32, u32) {
+        let image = DynamicImage::new_rgb8(width, height);
+        let resized = resize(&image, 100, 100, upscale, fit, [255, 255, 255]);
+
+        (resized.width(), resized.height())
+    }
+
+    #[test]
+    fn inside_keeps_the_aspect_ratio() {
+        assert_eq!(resized(200, 100, false, Fit::Inside), (100, 50));
+        assert_eq!(resized(50, 20, false, Fit::Inside), (50, 20));
+        assert_eq!(resized(50, 20, true, Fit::Inside), (100, 40));
+    }
+
+    #[test]
+    fn contain_fills_the_box_with_the_background() {
+        assert_eq!(resized(200, 100, false, Fit::Contain), (100, 100));
+        assert_eq!(resized(50, 20, false, Fit::Contain), (50, 20));
+        assert_eq!(resized(50, 20, true, Fit::Contain), (100, 100));
+    }
+
+    #[test]
+    fn cover_crops_without_enlarging() {
+        assert_eq!(resized(200, 100, false, Fit::Cover), (100, 100));
+        assert_eq!(resized(200, 50, false, Fit::Cover), (50, 50));
+        assert_eq!(resized(50, 20, false, Fit::Cover), (50, 20));
+        assert_eq!(resized(50, 20, true, Fit::Cover), (100, 100));
+    }
+
+    #[test]
+    fn fill_stretches_without_enlarging() {
+        assert_eq!(resized(200, 300, false, Fit::Fill), (100, 100));
+        assert_eq!(resized(200, 50, false, Fit::Fill), (100, 50));
+        assert_eq!(resized(50, 20, false, Fit::Fill), (50, 20));
+        assert_eq!(resized(50, 20, true, Fit::Fill), (100, 100));
+    }
+
+    #[test]
+    fn every_extension_is_a_supported_output() {
+        assert_eq!(supported_outputs(), ["jpg", "webp", "avif"]);
+    }
+
+    #[test]
+    fn corrupted_encodes_are_detected() {
+        let image = DynamicImage::new_rgb8(16, 8);
+        for (config, format) in [
+            (OptimizationConfig::Webp { quality: 75.0, prefer_quality: false, method: 3, alpha_quality: 50, lossless: false }, ImageFormat::WebP),
+            (OptimizationConfig::Jpeg { quality: 75.0, prefer_quality: false }, ImageFormat::Jpeg),
+        ] {
+            let encoded = optimize(&image, config).unwrap().data().to_vec();
+            assert!(verify(&encoded, format, 16, 8).is_ok());
+            assert!(verify(&encoded, format, 8, 16).is_err());
+            assert!(verify(&encoded[..encoded.len() / 2], format, 16, 8).is_err());
+            assert!(verify(&vec![0; encoded.len()], format, 16, 8).is_err());
+        }
+    }
+}
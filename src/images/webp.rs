@@ -21,11 +21,11 @@ impl Into<Webp> for WebPMemory {
     }
 }
 
-pub fn to_webp(image: &DynamicImage, quality: f32, autofilter: bool) -> Webp {
+pub fn to_webp(image: &DynamicImage, quality: f32, autofilter: bool, method: u8, alpha_quality: u8, lossless: bool) -> Webp {
     let mut config = WebPConfig::new().unwrap();
     config.quality = quality;
-    config.lossless = 0;
-    config.alpha_quality = 50;
+    config.lossless = lossless as c_int;
+    config.alpha_quality = alpha_quality as c_int;
     config.alpha_compression = 1;
     config.alpha_filtering = 0;
     config.autofilter = autofilter as c_int;
@@ -33,7 +33,7 @@ pub fn to_webp(image: &DynamicImage, quality: f32, autofilter: bool) -> Webp {
     config.filter_strength = 50;
     config.filter_type = 0;
     config.use_sharp_yuv = 0;
-    config.method = 3;
+    config.method = method as c_int;
 
     Encoder::from_image(image)
         .expect("Unsupported format")
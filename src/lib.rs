@@ -8,9 +8,16 @@ mod macros;
 
 mod backend;
 mod cache;
+mod conditionals;
 mod config;
 mod images;
 mod error;
+mod limiter;
+mod meta;
+mod pyramid;
+mod query;
+mod range;
+mod srcset;
 mod utils;
 
 use log4rs::append::file::FileAppender;
@@ -20,7 +27,7 @@ use log::LevelFilter;
 use varnish::vcl::ctx::Ctx;
 use varnish::vcl::backend::{Backend, VCLBackendPtr};
 use crate::error::Error;
-use crate::backend::{FileBackend, FileTransfer};
+use crate::backend::{BodyTransfer, FileBackend};
 use crate::cache::Cache;
 use crate::config::{Config, Logger as LoggerConfig};
 
@@ -28,7 +35,7 @@ use crate::config::{Config, Logger as LoggerConfig};
 type new = Impress;
 
 struct Impress {
-    backend: Backend<FileBackend, FileTransfer>,
+    backend: Backend<FileBackend, BodyTransfer>,
 }
 
 impl Impress {
@@ -49,6 +56,39 @@ impl Impress {
     pub fn backend(&self, _ctx: &Ctx) -> VCLBackendPtr {
         self.backend.vcl_ptr()
     }
+
+    pub fn prewarm(&self, _ctx: &Ctx, paths: &str) -> Result<i64, Error> {
+        Ok(self.backend.get_inner().cache().prewarm(&image_ids(paths))? as i64)
+    }
+
+    pub fn purge(&self, _ctx: &Ctx, paths: &str, size: Option<&str>) -> Result<i64, Error> {
+        Ok(self.backend.get_inner().cache().purge(&image_ids(paths), size)? as i64)
+    }
+
+    pub fn clear(&self, _ctx: &Ctx) -> Result<i64, Error> {
+        Ok(self.backend.get_inner().cache().clear()? as i64)
+    }
+
+    pub fn stats(&self, _ctx: &Ctx, path: &str) -> Result<String, Error> {
+        let image_id = image_ids(path).first().copied().unwrap_or("");
+        Ok(self.backend.get_inner().cache().stats(image_id)?.unwrap_or_default())
+    }
+
+    pub fn supported_inputs(&self, _ctx: &Ctx) -> String {
+        images::supported_inputs().join(",")
+    }
+
+    pub fn supported_outputs(&self, _ctx: &Ctx) -> String {
+        images::supported_outputs().join(",")
+    }
+}
+
+//paths are image ids, their extension is optional
+fn image_ids(paths: &str) -> Vec<&str> {
+    paths.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|path| !path.is_empty())
+        .filter_map(|path| utils::decompose_filename(path.trim_start_matches('/')).0)
+        .collect()
 }
 
 fn setup_logging(logger_config: &LoggerConfig) {
@@ -0,0 +1,77 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+//counts the files currently opened by transfers, a permit
+//has to be acquired before opening a file and is released
+//when it gets dropped along with its transfer
+#[derive(Clone)]
+pub struct FileLimiter {
+    max: Option<usize>,
+    opened: Arc<AtomicUsize>,
+}
+
+pub struct FilePermit {
+    opened: Arc<AtomicUsize>,
+}
+
+impl FileLimiter {
+    pub fn new(max: Option<usize>) -> Self {
+        FileLimiter {
+            max,
+            opened: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn acquire(&self) -> Option<FilePermit> {
+        let acquired = self.opened.fetch_update(Ordering::AcqRel, Ordering::Acquire, |opened| {
+            match self.max {
+                Some(max) if opened >= max => None,
+                _ => Some(opened + 1),
+            }
+        });
+
+        acquired.ok().map(|_| FilePermit {
+            opened: self.opened.clone(),
+        })
+    }
+}
+
+impl Drop for FilePermit {
+    fn drop(&mut self) {
+        self.opened.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn permits_are_refused_past_the_maximum_until_one_is_dropped() {
+        let limiter = FileLimiter::new(Some(2));
+
+        let first = limiter.acquire().unwrap();
+        let _second = limiter.acquire().unwrap();
+        assert!(limiter.acquire().is_none());
+
+        drop(first);
+        assert!(limiter.acquire().is_some());
+    }
+
+    #[test]
+    fn clones_share_their_permits() {
+        let limiter = FileLimiter::new(Some(1));
+        let clone = limiter.clone();
+
+        let _permit = limiter.acquire().unwrap();
+        assert!(clone.acquire().is_none());
+    }
+
+    #[test]
+    fn permits_are_unlimited_without_maximum() {
+        let limiter = FileLimiter::new(None);
+        let permits = (0..1000).filter_map(|_| limiter.acquire()).collect::<Vec<_>>();
+
+        assert_eq!(permits.len(), 1000);
+    }
+}
@@ -16,11 +16,15 @@ macro_rules! debug_file {
 
 #[macro_export]
 macro_rules! debug_header {
-    ($beresp:ident, $name:expr, $message:expr) => {
-        $beresp.set_header($name, $message.replace("\n", " "))?;
+    ($config:expr, $beresp:ident, $name:expr, $message:expr) => {
+        if $config.debug_headers.unwrap_or(false) {
+            $beresp.set_header($name, &$message.replace(|c: char| c.is_control(), " "))?;
+        }
     };
-    (abort: $beresp:expr, $name:expr, $message:expr) => {
-        $beresp.set_header($name, $message.replace("\n", " "))?;
+    (abort: $config:expr, $beresp:expr, $name:expr, $message:expr) => {
+        if $config.debug_headers.unwrap_or(false) {
+            $beresp.set_header($name, &$message.replace(|c: char| c.is_control(), " "))?;
+        }
         return Ok(None);
     };
 }
@@ -0,0 +1,32 @@
+use image::ImageFormat;
+use serde::Serialize;
+use crate::cache::FetchResult;
+use crate::error::Error;
+use crate::images;
+
+#[derive(Serialize, Debug)]
+pub struct Meta {
+    pub format: String,
+    pub width: u32,
+    pub height: u32,
+    pub has_alpha: bool,
+    pub size: usize,
+    pub etag: String,
+}
+
+impl Meta {
+    //describes the file that would have been served for the same request
+    pub fn new(result: &FetchResult, etag: &str) -> Result<Meta, Error> {
+        let format = ImageFormat::from_path(&result.path)?;
+        let (width, height, has_alpha) = images::describe(&result.path)?;
+
+        Ok(Meta {
+            format: format.extensions_str().first().unwrap_or(&"").to_string(),
+            width,
+            height,
+            has_alpha,
+            size: result.size as usize,
+            etag: etag.to_owned(),
+        })
+    }
+}
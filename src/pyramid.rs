@@ -0,0 +1,67 @@
+use std::fs;
+use std::path::Path;
+use serde::Deserialize;
+use crate::config::{Config, Fit, Size};
+
+//precomputed resolution of an image, its path is relative to the manifest
+#[derive(Deserialize, Clone, Debug)]
+pub struct Level {
+    pub width: u32,
+    pub height: u32,
+    pub path: String,
+}
+
+//levels listed in the manifest of an image, images without
+//manifest or with an invalid one are resized from the original
+pub fn levels(config: &Config, base_image_path: &str) -> Vec<Level> {
+    let Some(pattern) = &config.pyramid_manifest else {
+        return Vec::new();
+    };
+
+    let stem = Path::new(base_image_path).with_extension("");
+    let manifest = pattern.replace("{stem}", &stem.to_string_lossy());
+
+    let Ok(content) = fs::read_to_string(&manifest) else {
+        return Vec::new();
+    };
+
+    let levels = match serde_json::from_str::<Vec<Level>>(&content) {
+        Ok(levels) => levels,
+        Err(error) => {
+            warn!("Invalid pyramid manifest {}: {}", manifest, error);
+            return Vec::new();
+        }
+    };
+
+    //levels are served like the images, they have to resolve inside the roots
+    let directory = Path::new(&manifest).parent().unwrap_or(Path::new(""));
+    levels.into_iter()
+        .filter_map(|level| {
+            let path = directory.join(&level.path);
+            if !config.is_inside_roots(&path) || !config.is_allowed_symlink(&path) {
+                warn!("Pyramid level {} of manifest {} is outside of the roots, it will be ignored", level.path, manifest);
+                return None;
+            }
+
+            Some(Level {
+                path: path.to_string_lossy().to_string(),
+                ..level
+            })
+        })
+        .collect()
+}
+
+//smallest level the size can be resized from without losing any detail, levels keep the
+//aspect ratio so reaching the size in one dimension is enough unless the image gets cropped
+//or stretched to fill the whole size
+pub fn closest<'a>(levels: &'a [Level], size: &Size) -> Option<&'a Level> {
+    let fills = matches!(size.fit.unwrap_or(Fit::Inside), Fit::Cover | Fit::Fill);
+
+    levels.iter()
+        .filter(|level| if fills {
+            level.width >= size.width && level.height >= size.height
+        } else {
+            level.width >= size.width || level.height >= size.height
+        })
+        .min_by_key(|level| level.width as u64 * level.height as u64)
+}
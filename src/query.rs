@@ -0,0 +1,114 @@
+use std::borrow::Cow;
+use crate::error::Error;
+
+//query parameters understood by the backend, unknown parameters are
+//ignored so that clients can still add their own cache busters
+#[derive(Default, Debug)]
+pub struct QueryParams {
+    pub srcset: Option<String>,
+    pub original: bool,
+    pub meta: bool,
+}
+
+impl QueryParams {
+    pub fn parse(query: Option<&str>) -> Result<QueryParams, Error> {
+        let mut params = QueryParams::default();
+        let mut seen = Vec::new();
+
+        let pairs = query.unwrap_or("").split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| pair.split_once('=').unwrap_or((pair, "")));
+
+        for (key, value) in pairs {
+            let key = decode(key)?;
+            let value = decode(value)?;
+
+            if !matches!(key.as_ref(), "srcset" | "original" | "meta") {
+                continue;
+            }
+
+            //repeated parameters are ambiguous, the messages only contain known
+            //keys as they end up in the logs and the debug headers
+            if seen.contains(&key) {
+                return Err(Error::BadRequest(format!("Repeated query parameter {}", key)));
+            }
+
+            match key.as_ref() {
+                "srcset" => params.srcset = Some(value.to_string()),
+                "original" => params.original = parse_flag(&key, &value)?,
+                "meta" => params.meta = parse_flag(&key, &value)?,
+                _ => {}
+            }
+
+            seen.push(key);
+        }
+
+        Ok(params)
+    }
+}
+
+fn decode(value: &str) -> Result<Cow<'_, str>, Error> {
+    urlencoding::decode(value).map_err(|_| Error::BadRequest(String::from("Invalid query parameter encoding")))
+}
+
+//flags without a value are enabled
+fn parse_flag(key: &str, value: &str) -> Result<bool, Error> {
+    match value {
+        "" | "1" | "true" => Ok(true),
+        "0" | "false" => Ok(false),
+        _ => Err(Error::BadRequest(format!("Invalid value for query parameter {}", key))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults_without_query() {
+        let params = QueryParams::parse(None).unwrap();
+
+        assert_eq!(params.srcset, None);
+        assert!(!params.original);
+        assert!(!params.meta);
+    }
+
+    #[test]
+    fn parses_known_parameters() {
+        let params = QueryParams::parse(Some("original&meta=1&srcset=low%2Chigh")).unwrap();
+
+        assert_eq!(params.srcset.as_deref(), Some("low,high"));
+        assert!(params.original);
+        assert!(params.meta);
+    }
+
+    #[test]
+    fn parses_flags() {
+        assert!(QueryParams::parse(Some("original=true")).unwrap().original);
+        assert!(!QueryParams::parse(Some("original=0")).unwrap().original);
+        assert!(!QueryParams::parse(Some("original=false")).unwrap().original);
+    }
+
+    #[test]
+    fn ignores_unknown_parameters() {
+        let params = QueryParams::parse(Some("utm_source=mail&original=1&a=1&a=2")).unwrap();
+
+        assert!(params.original);
+    }
+
+    #[test]
+    fn rejects_repeated_parameters() {
+        assert!(matches!(QueryParams::parse(Some("meta=1&meta=0")), Err(Error::BadRequest(_))));
+        assert!(matches!(QueryParams::parse(Some("meta&m%65ta")), Err(Error::BadRequest(_))));
+    }
+
+    #[test]
+    fn rejects_invalid_flags_without_echoing_them() {
+        let Err(Error::BadRequest(message)) = QueryParams::parse(Some("original=%0d%0aSet-Cookie:%20x=y")) else {
+            panic!("Invalid flag accepted");
+        };
+
+        assert!(!message.contains("Set-Cookie"));
+        assert!(!message.contains('\n'));
+    }
+}
@@ -0,0 +1,124 @@
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum Range {
+    Full,
+    Partial { start: u64, end: u64 },
+    Multiple(Vec<(u64, u64)>),
+    Unsatisfiable,
+}
+
+impl Range {
+    //parses a `range` header against a body of `size` bytes, malformed and
+    //unsupported headers are ignored and the whole body is served
+    pub fn parse(header: Option<&str>, size: u64) -> Range {
+        let Some(ranges) = header.and_then(|header| header.trim().strip_prefix("bytes=")) else {
+            return Range::Full;
+        };
+
+        let mut satisfiable = Vec::new();
+        for range in ranges.split(',') {
+            match Range::parse_one(range, size) {
+                Range::Partial { start, end } => satisfiable.push((start, end)),
+                Range::Unsatisfiable => continue,
+                _ => return Range::Full,
+            }
+        }
+
+        satisfiable.sort_unstable();
+
+        //overlapping ranges would let a small request produce a response
+        //much larger than the image, they get the whole body instead
+        if satisfiable.windows(2).any(|pair| pair[1].0 <= pair[0].1) {
+            return Range::Full;
+        }
+
+        match satisfiable.as_slice() {
+            [] => Range::Unsatisfiable,
+            [(start, end)] => Range::Partial { start: *start, end: *end },
+            _ => Range::Multiple(satisfiable),
+        }
+    }
+
+    fn parse_one(range: &str, size: u64) -> Range {
+        let Some((start, end)) = range.trim().split_once('-') else {
+            return Range::Full;
+        };
+
+        let (start, end) = match (start.trim(), end.trim()) {
+            ("", "") => return Range::Full,
+            ("", suffix) => match suffix.parse::<u64>() {
+                Ok(0) => return Range::Unsatisfiable,
+                Ok(suffix) => (size.saturating_sub(suffix), size.saturating_sub(1)),
+                Err(_) => return Range::Full,
+            },
+            (start, "") => match start.parse::<u64>() {
+                Ok(start) => (start, size.saturating_sub(1)),
+                Err(_) => return Range::Full,
+            },
+            (start, end) => match (start.parse::<u64>(), end.parse::<u64>()) {
+                (Ok(start), Ok(end)) if start <= end => (start, end.min(size.saturating_sub(1))),
+                _ => return Range::Full,
+            },
+        };
+
+        if size == 0 || start >= size {
+            Range::Unsatisfiable
+        } else {
+            Range::Partial { start, end }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn full_without_header() {
+        assert_eq!(Range::parse(None, 100), Range::Full);
+    }
+
+    #[test]
+    fn single_ranges() {
+        assert_eq!(Range::parse(Some("bytes=0-9"), 100), Range::Partial { start: 0, end: 9 });
+        assert_eq!(Range::parse(Some("bytes=90-"), 100), Range::Partial { start: 90, end: 99 });
+        assert_eq!(Range::parse(Some("bytes=-10"), 100), Range::Partial { start: 90, end: 99 });
+        assert_eq!(Range::parse(Some("bytes=-200"), 100), Range::Partial { start: 0, end: 99 });
+        assert_eq!(Range::parse(Some("bytes=50-200"), 100), Range::Partial { start: 50, end: 99 });
+    }
+
+    #[test]
+    fn unsatisfiable_ranges() {
+        assert_eq!(Range::parse(Some("bytes=100-"), 100), Range::Unsatisfiable);
+        assert_eq!(Range::parse(Some("bytes=-0"), 100), Range::Unsatisfiable);
+        assert_eq!(Range::parse(Some("bytes=0-9"), 0), Range::Unsatisfiable);
+    }
+
+    #[test]
+    fn malformed_ranges_serve_the_whole_body() {
+        assert_eq!(Range::parse(Some("items=0-9"), 100), Range::Full);
+        assert_eq!(Range::parse(Some("bytes=9-0"), 100), Range::Full);
+        assert_eq!(Range::parse(Some("bytes=-"), 100), Range::Full);
+        assert_eq!(Range::parse(Some("bytes=a-b"), 100), Range::Full);
+        assert_eq!(Range::parse(Some("bytes=10"), 100), Range::Full);
+    }
+
+    #[test]
+    fn multiple_ranges() {
+        assert_eq!(Range::parse(Some("bytes=0-9, 20-29"), 100), Range::Multiple(vec![(0, 9), (20, 29)]));
+        assert_eq!(Range::parse(Some("bytes=20-29,0-9"), 100), Range::Multiple(vec![(0, 9), (20, 29)]));
+        assert_eq!(Range::parse(Some("bytes=0-9,-10"), 100), Range::Multiple(vec![(0, 9), (90, 99)]));
+    }
+
+    #[test]
+    fn multiple_ranges_drop_unsatisfiable_ones() {
+        assert_eq!(Range::parse(Some("bytes=0-9,200-299"), 100), Range::Partial { start: 0, end: 9 });
+        assert_eq!(Range::parse(Some("bytes=100-,200-299"), 100), Range::Unsatisfiable);
+    }
+
+    #[test]
+    fn overlapping_or_malformed_multiple_ranges_serve_the_whole_body() {
+        assert_eq!(Range::parse(Some("bytes=0-50,40-60"), 100), Range::Full);
+        assert_eq!(Range::parse(Some("bytes=0-9,9-19"), 100), Range::Full);
+        assert_eq!(Range::parse(Some("bytes=0-9,a-b"), 100), Range::Full);
+    }
+}
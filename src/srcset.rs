@@ -0,0 +1,47 @@
+use itertools::Itertools;
+use serde::Serialize;
+use crate::config::Config;
+
+#[derive(Serialize, Debug)]
+pub struct Srcset {
+    pub srcset: String,
+    pub variants: Vec<Variant>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Variant {
+    pub size: String,
+    pub width: u32,
+    pub height: u32,
+    pub url: String,
+}
+
+impl Srcset {
+    //filter is a comma separated list of size names, every size is listed if empty
+    pub fn new(config: &Config, path: &str, ext: Option<&str>, filter: &str) -> Srcset {
+        let filter = filter.split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .collect::<Vec<&str>>();
+
+        let variants = config.sizes.iter()
+            .filter(|(name, size)| size.matches(path) && (filter.is_empty() || filter.contains(&name.as_str())))
+            .sorted_by_key(|(name, size)| (size.width, size.height, name.to_string()))
+            .map(|(name, size)| Variant {
+                size: name.to_owned(),
+                width: size.width,
+                height: size.height,
+                url: config.build_url(name, path, ext),
+            })
+            .collect::<Vec<Variant>>();
+
+        let srcset = variants.iter()
+            .map(|variant| format!("{} {}w", variant.url, variant.width))
+            .join(", ");
+
+        Srcset {
+            srcset,
+            variants,
+        }
+    }
+}
@@ -1,5 +1,10 @@
+use std::borrow::Cow;
 use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
 use std::path::Path;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use crate::error::Error;
 
 pub fn decompose_filename(path: &str) -> (Option<&str>, Option<&str>) {
     let path = Path::new(path);
@@ -11,4 +16,134 @@ pub fn decompose_filename(path: &str) -> (Option<&str>, Option<&str>) {
     };
 
     (stem, extension)
-}
\ No newline at end of file
+}
+
+pub fn collapse_slashes(path: &str) -> Cow<'_, str> {
+    if !path.contains("//") {
+        return Cow::Borrowed(path);
+    }
+
+    let mut collapsed = String::with_capacity(path.len());
+    for c in path.chars() {
+        if c != '/' || !collapsed.ends_with('/') {
+            collapsed.push(c);
+        }
+    }
+
+    Cow::Owned(collapsed)
+}
+
+pub fn split_query(url: &str) -> (&str, Option<&str>) {
+    match url.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (url, None),
+    }
+}
+
+//percent encoded nul bytes or new lines would otherwise end up in the paths and logs
+pub fn decode_path(path: &str) -> Result<Cow<'_, str>, Error> {
+    let Ok(decoded) = urlencoding::decode(path) else {
+        return Err(Error::BadRequest(String::from("The path is not valid percent encoded UTF-8")));
+    };
+
+    if decoded.chars().any(|c| c.is_ascii_control()) {
+        return Err(Error::BadRequest(String::from("Control characters are not allowed in the path")));
+    }
+
+    Ok(decoded)
+}
+
+//fnv-1a, unlike the std hashers it is guaranteed to give the same hash
+//across rust versions which matters for anything persisted on disk
+pub fn stable_hash<T: AsRef<[u8]>>(data: T) -> u64 {
+    stable_hash_from(FNV_OFFSET_BASIS, data.as_ref())
+}
+
+pub fn stable_hash_file<P: AsRef<Path>>(path: P) -> Result<u64, Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut hash = FNV_OFFSET_BASIS;
+
+    loop {
+        let buffer = reader.fill_buf()?;
+        if buffer.is_empty() {
+            return Ok(hash);
+        }
+
+        hash = stable_hash_from(hash, buffer);
+
+        let length = buffer.len();
+        reader.consume(length);
+    }
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+
+fn stable_hash_from(hash: u64, data: &[u8]) -> u64 {
+    data.iter().fold(hash, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
+}
+
+const IMF_FIXDATE: &str = "%a, %d %b %Y %H:%M:%S GMT";
+const RFC_850: &str = "%A, %d-%b-%y %H:%M:%S GMT";
+const ASCTIME: &str = "%a %b %e %H:%M:%S %Y";
+
+pub fn http_date(date: &DateTime<Utc>) -> String {
+    date.format(IMF_FIXDATE).to_string()
+}
+
+//recipients have to accept the three formats of http dates, only the first one is sent
+pub fn parse_http_date(date: &str) -> Option<DateTime<Utc>> {
+    [IMF_FIXDATE, RFC_850, ASCTIME].iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(date.trim(), format).ok())
+        .map(|date| date.and_utc())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use super::*;
+
+    #[test]
+    fn parses_the_three_http_date_formats() {
+        let expected = Utc.with_ymd_and_hms(1994, 11, 6, 8, 49, 37).unwrap();
+
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(expected));
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), Some(expected));
+        assert_eq!(parse_http_date("Sun Nov  6 08:49:37 1994"), Some(expected));
+    }
+
+    #[test]
+    fn rejects_invalid_http_dates() {
+        assert_eq!(parse_http_date(""), None);
+        assert_eq!(parse_http_date("yesterday"), None);
+        assert_eq!(parse_http_date("1994-11-06T08:49:37Z"), None);
+    }
+
+    #[test]
+    fn formats_http_dates() {
+        let date = Utc.with_ymd_and_hms(1994, 11, 6, 8, 49, 37).unwrap();
+
+        assert_eq!(http_date(&date), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(parse_http_date(&http_date(&date)), Some(date));
+    }
+
+    #[test]
+    fn collapses_duplicate_slashes() {
+        assert_eq!(collapse_slashes("/media/low/photo.jpg"), "/media/low/photo.jpg");
+        assert_eq!(collapse_slashes("//media///low//photo.jpg"), "/media/low/photo.jpg");
+        assert_eq!(collapse_slashes("/media/low/"), "/media/low/");
+        assert!(matches!(collapse_slashes("/media/low/photo.jpg"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn decodes_percent_encoded_paths() {
+        assert_eq!(decode_path("/media/my%20photo.jpg").unwrap(), "/media/my photo.jpg");
+        assert_eq!(decode_path("/media/caf%C3%A9.jpg").unwrap(), "/media/café.jpg");
+    }
+
+    #[test]
+    fn rejects_invalid_paths_as_bad_requests() {
+        for path in ["/media/%FF.jpg", "/media/photo%00.jpg", "/media/photo%0A.jpg", "/media/photo%0D%0A.jpg"] {
+            assert!(matches!(decode_path(path), Err(Error::BadRequest(_))), "{}", path);
+        }
+    }
+}